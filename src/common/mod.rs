@@ -1,36 +1,90 @@
-﻿use std::marker::PhantomData;
-use std::sync::{Arc, mpsc, Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+﻿pub mod permissions;
+pub mod queue;
+pub mod shutdown;
+
+use serde::Serialize;
 use uuid::Uuid;
-use crate::Log;
-use crate::logger::Logger;
 
+#[derive(Serialize)]
 pub struct Event {
     pub(crate) id: Uuid,
+    #[serde(flatten)]
     pub(crate) event_type: EventType
 }
 
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
 pub enum EventType {
     Test,
     RunResult(RunResultEvent),
     NodeStateChange(NodeStateChangeEvent),
+    ActionResult(ActionResultEvent),
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RunResultEvent {
     pub(crate) successful: bool,
     pub(crate) message: String,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct NodeStateChangeEvent {
     pub node: String,
     pub old_state: u8,
     pub new_state: u8,
 } 
 
+/// Raised for every action the orchestrator carries out, whether it succeeded or not.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionResultEvent {
+    pub action_id: Uuid,
+    /// The node the action was for, `None` if it was not for one.
+    pub node: Option<String>,
+    pub successful: bool,
+    pub message: String,
+    /// Why the action failed, `None` if it succeeded.
+    pub failure: Option<ActionFailure>,
+}
+
+/// Why an action was not carried out, so a node that is offline can be told apart from one that misbehaves.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ActionFailure {
+    /// The principal that asked for it is not allowed to.
+    PermissionDenied,
+    /// Too many actions were already waiting to be carried out.
+    Rejected,
+    /// The node name could not be resolved to an address.
+    UnknownNode,
+    /// The node could not be connected to.
+    Unreachable,
+    /// The node did not respond in time, it may be offline.
+    Timeout,
+    /// The node responded, but with an error status or a body that could not be understood.
+    BadResponse,
+}
+
 pub struct Command {
     pub(crate) id: Uuid,
     pub(crate) command_type: CommandType,
+    /// Who asked for the command, `None` for commands raised by the controller itself.
+    pub(crate) principal: Option<Principal>,
+}
+
+/// An authenticated caller of the http api.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub method: AuthMethod,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthMethod {
+    ApiKey,
+    BearerToken,
 }
 
 pub enum CommandType {
@@ -70,12 +124,37 @@ pub struct ChangeNodeStateAction {
 
 pub struct ActionResult {
     pub(crate) id: Uuid,
+    pub(crate) node: Option<String>,
     pub(crate) successful: bool,
     pub(crate) message: String,
+    pub(crate) failure: Option<ActionFailure>,
     pub(crate) ops: Vec<Operation>,
 }
 
 pub enum Operation {
     Test,
     RaiseEvent(Event),
-}
\ No newline at end of file
+}
+
+impl Event {
+    /// The node the event is about, if it is about one.
+    pub fn node(&self) -> Option<&str> {
+        match &self.event_type {
+            EventType::NodeStateChange(change) => Some(&change.node),
+            EventType::ActionResult(result) => result.node.as_deref(),
+            EventType::Test | EventType::RunResult(_) => None
+        }
+    }
+}
+
+impl EventType {
+    /// The name the event type is serialized with.
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            EventType::Test => "test",
+            EventType::RunResult(_) => "runResult",
+            EventType::NodeStateChange(_) => "nodeStateChange",
+            EventType::ActionResult(_) => "actionResult",
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::common::Principal;
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    /// Read the state of a node.
+    ReadState,
+    /// Change the state of a node.
+    ChangeState,
+}
+
+/// A permission on a set of nodes, named directly or by tag. `"*"` in `nodes` covers every node.
+#[derive(Clone, Deserialize)]
+pub struct Grant {
+    pub permission: Permission,
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Which principals may do what, and to which nodes. Anything not granted is refused.
+///
+/// ```json
+/// {
+///     "roles": {
+///         "viewer": [ { "permission": "readState", "nodes": [ "*" ] } ],
+///         "kitchen-operator": [ { "permission": "changeState", "tags": [ "kitchen" ] } ]
+///     },
+///     "assignments": { "alice": [ "viewer", "kitchen-operator" ] },
+///     "tags": { "kitchen": [ "node1", "node2" ] }
+/// }
+/// ```
+#[derive(Clone, Default, Deserialize)]
+pub struct AccessPolicy {
+    /// Grants by role name.
+    #[serde(default)]
+    pub roles: HashMap<String, Vec<Grant>>,
+    /// Role names by principal name.
+    #[serde(default)]
+    pub assignments: HashMap<String, Vec<String>>,
+    /// Node names by tag.
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+}
+
+impl AccessPolicy {
+    /// Check if `principal` has `permission` on `node`.
+    /// With no node, check if it has the permission on any node at all.
+    pub fn allows(&self, principal: &Principal, permission: Permission, node: Option<&str>) -> bool {
+        let roles = match self.assignments.get(&principal.name) {
+            Some(roles) => roles,
+            None => return false
+        };
+
+        roles.iter()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .filter(|grant| grant.permission == permission)
+            .any(|grant| match node {
+                None => true,
+                Some(node) => self.covers(grant, node)
+            })
+    }
+
+    fn covers(&self, grant: &Grant, node: &str) -> bool {
+        if grant.nodes.iter().any(|n| n == "*" || n == node) {
+            return true;
+        }
+
+        grant.tags.iter().any(|tag| match self.tags.get(tag) {
+            Some(nodes) => nodes.iter().any(|n| n == node),
+            None => false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::AuthMethod;
+    use super::*;
+
+    fn policy() -> AccessPolicy {
+        serde_json::from_str(r#"{
+            "roles": {
+                "viewer": [ { "permission": "readState", "nodes": [ "*" ] } ],
+                "kitchen-operator": [ { "permission": "changeState", "tags": [ "kitchen" ] } ],
+                "hall-operator": [ { "permission": "changeState", "nodes": [ "hall" ] } ],
+                "missing-tag": [ { "permission": "changeState", "tags": [ "garden" ] } ]
+            },
+            "assignments": {
+                "alice": [ "viewer", "kitchen-operator" ],
+                "bob": [ "hall-operator", "no-such-role" ],
+                "carol": [ "missing-tag" ]
+            },
+            "tags": { "kitchen": [ "node1", "node2" ] }
+        }"#).unwrap()
+    }
+
+    fn principal(name: &str) -> Principal {
+        Principal { name: name.to_string(), method: AuthMethod::ApiKey }
+    }
+
+    #[test]
+    fn wildcard_covers_every_node() {
+        let policy = policy();
+
+        for node in ["node1", "hall", "anything"] {
+            assert!(policy.allows(&principal("alice"), Permission::ReadState, Some(node)), "{}", node);
+        }
+
+        // Only for the permission granted.
+        assert!(!policy.allows(&principal("alice"), Permission::ChangeState, Some("hall")));
+    }
+
+    #[test]
+    fn tags_grant_their_nodes() {
+        let policy = policy();
+
+        assert!(policy.allows(&principal("alice"), Permission::ChangeState, Some("node1")));
+        assert!(policy.allows(&principal("alice"), Permission::ChangeState, Some("node2")));
+        assert!(!policy.allows(&principal("alice"), Permission::ChangeState, Some("node3")));
+        // A tag with no nodes behind it grants nothing.
+        assert!(!policy.allows(&principal("carol"), Permission::ChangeState, Some("garden")));
+    }
+
+    #[test]
+    fn named_nodes_are_matched_exactly() {
+        let policy = policy();
+
+        assert!(policy.allows(&principal("bob"), Permission::ChangeState, Some("hall")));
+        assert!(!policy.allows(&principal("bob"), Permission::ChangeState, Some("hallway")));
+        assert!(!policy.allows(&principal("bob"), Permission::ReadState, Some("hall")));
+    }
+
+    #[test]
+    fn any_node_needs_the_permission_somewhere() {
+        let policy = policy();
+
+        assert!(policy.allows(&principal("alice"), Permission::ReadState, None));
+        assert!(policy.allows(&principal("alice"), Permission::ChangeState, None));
+        assert!(policy.allows(&principal("bob"), Permission::ChangeState, None));
+        assert!(!policy.allows(&principal("bob"), Permission::ReadState, None));
+    }
+
+    #[test]
+    fn unknown_principals_and_roles_get_nothing() {
+        let policy = policy();
+
+        assert!(!policy.allows(&principal("mallory"), Permission::ReadState, None));
+        assert!(!policy.allows(&principal("mallory"), Permission::ReadState, Some("node1")));
+        assert!(!AccessPolicy::default().allows(&principal("alice"), Permission::ReadState, None));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::common::shutdown::Receive;
+
+/// What a queue does when something is sent to it while it is full.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowPolicy {
+    /// Wait until there is room.
+    Block,
+    /// Hand the item back with `QueueError::Full`.
+    Reject,
+    /// Make room by throwing away the item that has waited longest.
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct QueueSettings {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+/// Why an item could not be queued. The item is handed back.
+pub enum QueueError<T> {
+    Full(T),
+    /// The receiving end has gone away.
+    Disconnected(T),
+}
+
+/// The sending end of a bounded queue, which can be cloned for each producer.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving end of a bounded queue.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    settings: QueueSettings,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiving: bool,
+}
+
+impl QueueSettings {
+    pub fn create(capacity: usize, policy: OverflowPolicy) -> QueueSettings {
+        QueueSettings { capacity, policy }
+    }
+}
+
+/// A queue holding at most `settings.capacity` items, at least one.
+pub fn bounded<T>(settings: QueueSettings) -> (QueueSender<T>, QueueReceiver<T>) {
+    let settings = QueueSettings { capacity: settings.capacity.max(1), ..settings };
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            items: VecDeque::with_capacity(settings.capacity),
+            senders: 1,
+            receiving: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        settings,
+    });
+
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+impl<T> QueueSender<T> {
+    /// Queue an item, dealing with a full queue as its `OverflowPolicy` says.
+    pub fn send(&self, item: T) -> Result<(), QueueError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if !state.receiving {
+                return Err(QueueError::Disconnected(item));
+            }
+
+            if state.items.len() < self.shared.settings.capacity {
+                break;
+            }
+
+            match self.shared.settings.policy {
+                OverflowPolicy::Block => state = self.shared.not_full.wait(state).unwrap(),
+                OverflowPolicy::Reject => return Err(QueueError::Full(item)),
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    break;
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        drop(state);
+
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Check if the next item sent would be rejected.
+    /// Only certain when this is the one sender, otherwise another may take or free the last slot first.
+    pub fn would_reject(&self) -> bool {
+        self.shared.settings.policy == OverflowPolicy::Reject
+            && self.shared.state.lock().unwrap().items.len() >= self.shared.settings.capacity
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Wait for the next item. Fails once the queue is empty and every sender has gone away.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.shared.not_full.notify_one();
+                return Ok(item);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.shared.not_full.notify_one();
+                return Ok(item);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            state = self.shared.not_empty.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let item = self.shared.state.lock().unwrap().items.pop_front();
+
+        if item.is_some() {
+            self.shared.not_full.notify_one();
+        }
+
+        item
+    }
+}
+
+impl<T> Receive<T> for QueueReceiver<T> {
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        QueueReceiver::recv_timeout(self, timeout)
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        QueueReceiver::try_recv(self)
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+
+        QueueSender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiving = false;
+
+        // Senders waiting for room would otherwise wait forever.
+        self.shared.not_full.notify_all();
+    }
+}
+
+impl<T> fmt::Display for QueueError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Full(_) => write!(f, "Queue is full."),
+            QueueError::Disconnected(_) => write!(f, "Queue is no longer received from."),
+        }
+    }
+}
+
+impl<T> fmt::Debug for QueueError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    fn queue(capacity: usize, policy: OverflowPolicy) -> (QueueSender<u32>, QueueReceiver<u32>) {
+        bounded(QueueSettings::create(capacity, policy))
+    }
+
+    fn drain(receiver: &QueueReceiver<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| receiver.try_recv()).collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_items() {
+        let (sender, receiver) = queue(3, OverflowPolicy::DropOldest);
+
+        for i in 1..=5 {
+            assert!(sender.send(i).is_ok());
+        }
+
+        assert!(!sender.would_reject());
+        assert_eq!(drain(&receiver), [3, 4, 5]);
+    }
+
+    #[test]
+    fn reject_hands_the_item_back() {
+        let (sender, receiver) = queue(2, OverflowPolicy::Reject);
+
+        sender.send(1).ok().unwrap();
+        assert!(!sender.would_reject());
+        sender.send(2).ok().unwrap();
+        assert!(sender.would_reject());
+
+        assert!(matches!(sender.send(3), Err(QueueError::Full(3))));
+        assert_eq!(drain(&receiver), [1, 2]);
+        assert!(!sender.would_reject());
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (sender, receiver) = queue(1, OverflowPolicy::Block);
+        sender.send(1).ok().unwrap();
+
+        let blocked = thread::spawn(move || sender.send(2).is_ok());
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert!(blocked.join().unwrap());
+        assert_eq!(receiver.recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn capacity_is_at_least_one() {
+        let (sender, receiver) = queue(0, OverflowPolicy::Reject);
+
+        assert!(sender.send(1).is_ok());
+        assert!(matches!(sender.send(2), Err(QueueError::Full(2))));
+        assert_eq!(drain(&receiver), [1]);
+    }
+
+    #[test]
+    fn senders_are_told_once_the_receiver_is_gone() {
+        let (sender, receiver) = queue(1, OverflowPolicy::Block);
+        sender.send(1).ok().unwrap();
+
+        let blocked = thread::spawn(move || matches!(sender.send(2), Err(QueueError::Disconnected(2))));
+
+        thread::sleep(Duration::from_millis(50));
+        drop(receiver);
+
+        assert!(blocked.join().unwrap());
+    }
+
+    #[test]
+    fn receiver_is_told_once_every_sender_is_gone() {
+        let (sender, receiver) = queue(2, OverflowPolicy::Block);
+        let other = sender.clone();
+
+        sender.send(1).ok().unwrap();
+        drop(sender);
+        drop(other);
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert!(receiver.recv().is_err());
+        assert!(matches!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected)));
+    }
+
+    #[test]
+    fn policy_reads_from_config() {
+        let settings: QueueSettings = serde_json::from_str(r#"{ "capacity": 16, "policy": "dropOldest" }"#).unwrap();
+
+        assert_eq!(settings.capacity, 16);
+        assert_eq!(settings.policy, OverflowPolicy::DropOldest);
+    }
+}
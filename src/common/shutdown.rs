@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a subsystem waits for work before checking if it has been told to stop.
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tells the threads of a subsystem to stop. Clones share the same flag.
+#[derive(Clone, Default)]
+pub(crate) struct ShutdownSignal {
+    stopping: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    pub fn create() -> ShutdownSignal {
+        ShutdownSignal::default()
+    }
+
+    pub fn trigger(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// The receiving end of a channel or queue that a subsystem works through.
+pub(crate) trait Receive<T> {
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError>;
+
+    /// The next item if one is waiting.
+    fn try_recv(&self) -> Option<T>;
+}
+
+impl<T> Receive<T> for Receiver<T> {
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        Receiver::recv_timeout(self, timeout)
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        Receiver::try_recv(self).ok()
+    }
+}
+
+/// Handle everything sent on `receiver` until `signal` is triggered, then whatever is still queued.
+/// Also returns once every sender has gone away.
+pub(crate) fn receive_until<T, R, F>(receiver: &R, signal: &ShutdownSignal, mut handle: F) where
+    R: Receive<T>,
+    F: FnMut(T),
+{
+    while !signal.is_triggered() {
+        match receiver.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(item) => handle(item),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return
+        }
+    }
+
+    while let Some(item) = receiver.try_recv() {
+        handle(item);
+    }
+}
+
+/// Wait for a thread to finish, giving up at `deadline`.
+/// Returns false if it was still running, or if it panicked.
+pub(crate) fn join_until(thread: JoinHandle<()>, deadline: Instant) -> bool {
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    thread.join().is_ok()
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::common::queue::{OverflowPolicy, QueueSettings};
+use crate::http::access_log::{AccessLogFormat, AccessLogSettings, AccessLogTarget};
+use crate::http::cors::CorsSettings;
+use crate::http::error::HttpError;
+use crate::http::tls::{TlsClientFiles, TlsServerFiles};
+
+/// Where the access log is written unless the config says otherwise.
+const DEFAULT_ACCESS_LOG_PATH: &str = "access.log";
+
+/// Settings for the controller, read from a json file such as:
+///
+/// ```json
+/// {
+///     "tls": { "certificate": "cert.pem", "privateKey": "key.pem" },
+///     "nodeTls": { "trustedRoots": [ "ca.pem" ], "pinned": { "192.168.0.226": "node1.pem" } },
+///     "accessLog": { "format": "json", "target": "log" },
+///     "cors": { "allowedOrigins": [ "https://tools.example.com" ] },
+///     "queues": { "events": { "capacity": 4096, "policy": "dropOldest" } }
+/// }
+/// ```
+///
+/// Anything left out keeps its default.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerConfig {
+    /// Serve the api over https with this certificate, plain http if `None`.
+    pub tls: Option<TlsServerFiles>,
+    /// Connect to nodes over https, trusting the certificates given. Plain http if `None`.
+    pub node_tls: Option<TlsClientFiles>,
+    /// Combined Log Format appended to `access.log` if left out, `null` for no access log.
+    #[serde(default = "default_access_log")]
+    pub access_log: Option<AccessLogSettings>,
+    /// Browser pages on other origins that may call the api, none may if `None`.
+    pub cors: Option<CorsConfig>,
+    #[serde(default)]
+    pub queues: QueueLimits,
+}
+
+/// The size of each queue between the subsystems, and what happens when it is full.
+/// Policies are `block`, `reject` or `dropOldest`, a queue that is left out keeps its default.
+/// Requests queue in the http server, see `HttpServerSettings`.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct QueueLimits {
+    pub events: QueueSettings,
+    /// Should not block, the event loop queues commands and the orchestrator waits on
+    /// workers, which wait on the result handler, which waits on the event loop.
+    pub commands: QueueSettings,
+    pub results: QueueSettings,
+    /// Actions waiting for a worker.
+    pub jobs: QueueSettings,
+}
+
+/// The parts of `CorsSettings` that can be changed, the methods and headers allowed keep their defaults.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CorsConfig {
+    /// Origins such as `https://tools.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Only together with origins listed by name, a config with `*` and credentials is refused.
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+impl ControllerConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ControllerConfig, HttpError> {
+        let contents = fs::read(path)?;
+        let config: ControllerConfig = serde_json::from_slice(&contents).map_err(|_| HttpError::Serialization("Invalid controller config."))?;
+
+        if let Some(cors) = &config.cors {
+            cors.check()?;
+        }
+
+        Ok(config)
+    }
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        ControllerConfig {
+            tls: None,
+            node_tls: None,
+            access_log: default_access_log(),
+            cors: None,
+            queues: QueueLimits::default(),
+        }
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            events: QueueSettings::create(1024, OverflowPolicy::Block),
+            commands: QueueSettings::create(256, OverflowPolicy::Reject),
+            results: QueueSettings::create(256, OverflowPolicy::Block),
+            jobs: QueueSettings::create(64, OverflowPolicy::Block),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Refuse credentials for any origin, which would let every site act as the signed in user.
+    pub fn check(&self) -> Result<(), HttpError> {
+        match self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") {
+            true => Err(HttpError::Serialization("Invalid controller config, cors cannot allow credentials with \"*\", list the origins instead.")),
+            false => Ok(())
+        }
+    }
+
+    pub fn settings(&self) -> CorsSettings {
+        CorsSettings { allow_credentials: self.allow_credentials, ..CorsSettings::create(self.allowed_origins.clone()) }
+    }
+}
+
+fn default_access_log() -> Option<AccessLogSettings> {
+    Some(AccessLogSettings::create(AccessLogFormat::Combined, AccessLogTarget::File(PathBuf::from(DEFAULT_ACCESS_LOG_PATH))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_left_out_keep_their_defaults() {
+        let config: ControllerConfig = serde_json::from_str(r#"{ "queues": { "commands": { "capacity": 512, "policy": "dropOldest" } } }"#).unwrap();
+
+        assert_eq!(config.queues.commands.capacity, 512);
+        assert_eq!(config.queues.commands.policy, OverflowPolicy::DropOldest);
+        assert_eq!(config.queues.events.capacity, QueueLimits::default().events.capacity);
+        assert_eq!(config.queues.jobs.policy, OverflowPolicy::Block);
+    }
+
+    #[test]
+    fn cors_credentials_need_listed_origins() {
+        let cors = |json: &str| serde_json::from_str::<ControllerConfig>(json).unwrap().cors.unwrap();
+
+        assert!(cors(r#"{ "cors": { "allowedOrigins": [ "*" ], "allowCredentials": true } }"#).check().is_err());
+        assert!(cors(r#"{ "cors": { "allowedOrigins": [ "https://a.example", "*" ], "allowCredentials": true } }"#).check().is_err());
+        assert!(cors(r#"{ "cors": { "allowedOrigins": [ "*" ] } }"#).check().is_ok());
+        assert!(cors(r#"{ "cors": { "allowedOrigins": [ "https://a.example" ], "allowCredentials": true } }"#).check().is_ok());
+    }
+
+    #[test]
+    fn unknown_policy_is_rejected() {
+        assert!(serde_json::from_str::<ControllerConfig>(r#"{ "queues": { "jobs": { "capacity": 8, "policy": "ignore" } } }"#).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+use crate::{ActionResult, Command, Event, Log};
+use crate::common::queue::bounded;
+use crate::config::ControllerConfig;
+use crate::events::EventLoop;
+use crate::events::hub::EventHub;
+use crate::http::auth::AuthConfig;
+use crate::http::client::HttpConnectionPool;
+use crate::http::server::{HttpServer, HttpServerSettings};
+use crate::io::network::{NameResolver, NodeLinks, ResolverMessage};
+use crate::orchestrating::Orchestrator;
+use crate::results::ResultHandler;
+
+const AUTH_CONFIG_PATH: &str = "auth.json";
+
+/// Everything else that can be changed without a rebuild, see `ControllerConfig`.
+const CONTROLLER_CONFIG_PATH: &str = "controller.json";
+
+/// Served under `/ui/` if it exists.
+const STATIC_ROOT_PATH: &str = "www";
+
+pub struct Controller {
+    log: Log,
+    event_loop: EventLoop,
+    orchestrator: Orchestrator,
+    result_handler: ResultHandler,
+    http_server: HttpServer,
+    name_resolver: NameResolver,
+    event_hub: EventHub,
+}
+
+impl Controller {
+    pub fn start() -> Controller {
+        let log = Log::start().unwrap();
+        let config = controller_config(&log);
+        let queues = config.queues;
+
+        let (event_sender, event_receiver) = bounded::<Event>(queues.events);
+        let (command_sender, command_receiver) = bounded::<Command>(queues.commands);
+        let (result_sender, result_receiver) = bounded::<ActionResult>(queues.results);
+        let (nr_sender, nr_receiver) = channel::<ResolverMessage>();
+
+        let mut name_map = HashMap::new();
+        
+        name_map.insert("node1".to_string(), "192.168.0.226:80".to_string());
+        
+        let name_resolver = NameResolver::start(name_map, nr_receiver);
+
+        // Keep-alive connections to nodes, shared by the orchestrator workers and the http server.
+        let mut connection_pool = HttpConnectionPool::create(4, 32, Duration::from_secs(30));
+
+        if let Some(node_tls) = &config.node_tls {
+            connection_pool.set_tls(&node_tls.load().unwrap());
+        }
+
+        let nodes = NodeLinks { resolver: nr_sender, pool: connection_pool };
+        
+        // Events seen by the event loop, streamed to http clients. A client more than 64 events behind is dropped.
+        let event_hub = EventHub::create(256, 64);
+
+        let event_loop = EventLoop::start(command_sender.clone(), event_receiver, event_sender.clone(), event_hub.clone(), &log);
+
+        let auth = auth_config(&log);
+        let access = auth.as_ref().and_then(|a| a.access.clone()).map(Arc::new);
+
+        let orchestrator = Orchestrator::start(result_sender, command_receiver, command_sender.clone(), queues.jobs, nodes.clone(), access, &log);
+
+        let result_handler = ResultHandler::start(event_sender.clone(), result_receiver, &log);
+
+        let static_root = Path::new(STATIC_ROOT_PATH).is_dir().then(|| PathBuf::from(STATIC_ROOT_PATH));
+        let http_settings = HttpServerSettings { auth, static_root, tls: config.tls, access_log: config.access_log, cors: config.cors.map(|c| c.settings()), ..HttpServerSettings::default() };
+
+        let http_server = HttpServer::create("0.0.0.0:61409".to_string(), http_settings, event_sender, command_sender, nodes, event_hub.clone(), &log).unwrap();
+
+        Controller {
+            log,
+            event_loop,
+            orchestrator,
+            result_handler,
+            http_server,
+            name_resolver,
+            event_hub,
+        }
+    }
+
+    /// Stop taking requests, finish the commands and actions already queued, then stop every subsystem.
+    /// Anything still running after `timeout` is left behind.
+    pub fn shutdown(self, timeout: Duration) {
+        let logger = self.log.get_logger("controller".to_string());
+        let deadline = Instant::now() + timeout;
+
+        logger.log_info("Shutting down".to_string()).unwrap();
+
+        // Event streams and WebSockets only end once there is nothing more to wait for.
+        self.event_hub.close();
+
+        // In the order work flows through them, so each has stopped adding work for the next.
+        let stopped = [
+            ("http_server", self.http_server.shutdown(deadline)),
+            ("event_loop", self.event_loop.shutdown(deadline)),
+            ("orchestrator", self.orchestrator.shutdown(deadline)),
+            ("result_handler", self.result_handler.shutdown(deadline)),
+            ("name_resolver", self.name_resolver.shutdown(deadline)),
+        ];
+
+        for (name, _) in stopped.iter().filter(|(_, stopped)| !stopped) {
+            logger.log_warning(format!("{} did not stop in time", name)).unwrap();
+        }
+
+        logger.log_info("Shut down".to_string()).unwrap();
+
+        if !self.log.shutdown(deadline) {
+            println!("Log did not stop in time, some messages may be lost.");
+        }
+    }
+
+    pub fn raise_event(&self, event: Event) {
+        self.event_loop.raise_event(event);
+    }
+
+    pub fn queue_command(&self, command: Command) {
+        self.orchestrator.queue_command(command);
+    }
+}
+
+/// The api credentials, from `auth.json` in the working directory if it exists.
+fn auth_config(log: &Log) -> Option<AuthConfig> {
+    let logger = log.get_logger("controller".to_string());
+
+    match Path::new(AUTH_CONFIG_PATH).exists() {
+        true => Some(AuthConfig::from_file(AUTH_CONFIG_PATH).unwrap()),
+        false => {
+            logger.log_warning(format!("No {} found, the http api will not require authentication.", AUTH_CONFIG_PATH)).unwrap();
+            None
+        }
+    }
+}
+
+/// The controller settings, from `controller.json` in the working directory if it exists.
+fn controller_config(log: &Log) -> ControllerConfig {
+    let logger = log.get_logger("controller".to_string());
+
+    match Path::new(CONTROLLER_CONFIG_PATH).exists() {
+        true => ControllerConfig::from_file(CONTROLLER_CONFIG_PATH).unwrap(),
+        false => {
+            logger.log_info(format!("No {} found, using the default settings.", CONTROLLER_CONFIG_PATH)).unwrap();
+            ControllerConfig::default()
+        }
+    }
+}
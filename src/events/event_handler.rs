@@ -6,7 +6,7 @@ pub fn handle_event(event: Event, logger: &Logger) -> Vec<Command> {
     logger.log_info(format!("Handling event {}", event.id)).unwrap();
     match event.event_type {
         EventType::Test => {
-            vec! [ Command { id: event.id, command_type: CommandType::Test } ]
+            vec! [ Command { id: event.id, command_type: CommandType::Test, principal: None } ]
         }
         EventType::RunResult(run_result) => {
             match run_result.successful {
@@ -24,5 +24,9 @@ pub fn handle_event(event: Event, logger: &Logger) -> Vec<Command> {
             logger.log_info(format!("Node state change - Node {} Old state {} New state {}", "", event_data.old_state, event_data.new_state)).unwrap();
             vec! []
         }
+        EventType::ActionResult(_) => {
+            // Already logged by the result handler, raised so it can be watched.
+            vec! []
+        }
     }
 }
\ No newline at end of file
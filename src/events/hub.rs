@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use crate::common::Event;
+
+/// Hands every event the `EventLoop` sees to anyone subscribed, such as http event streams.
+/// The most recent events are kept so a subscriber that reconnects can catch up on what it missed.
+/// A subscriber that falls too far behind is dropped rather than held in memory, and can catch up the same way.
+/// Clones share the same subscribers and history.
+#[derive(Clone)]
+pub(crate) struct EventHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+/// An event as it was published, serialized once for every subscriber.
+pub(crate) struct PublishedEvent {
+    /// Increases by one with each event, used to resume a subscription.
+    pub sequence: u64,
+    pub event_type: &'static str,
+    pub node: Option<String>,
+    /// The event as json.
+    pub data: String,
+}
+
+struct HubState {
+    next_sequence: u64,
+    history: VecDeque<Arc<PublishedEvent>>,
+    history_size: usize,
+    subscribers: Vec<SyncSender<Arc<PublishedEvent>>>,
+    /// The most events waiting for a subscriber before it is dropped.
+    subscriber_capacity: usize,
+    /// Set by `close`, no one is subscribed from then on.
+    closed: bool,
+}
+
+impl EventHub {
+    pub fn create(history_size: usize, subscriber_capacity: usize) -> EventHub {
+        EventHub {
+            state: Arc::new(Mutex::new(HubState {
+                next_sequence: 1,
+                history: VecDeque::with_capacity(history_size),
+                history_size,
+                subscribers: Vec::new(),
+                subscriber_capacity: subscriber_capacity.max(1),
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn publish(&self, event: &Event) {
+        let data = match serde_json::to_string(event) {
+            Ok(data) => data,
+            Err(_) => return
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        let published = Arc::new(PublishedEvent {
+            sequence: state.next_sequence,
+            event_type: event.event_type.get_str(),
+            node: event.node().map(|n| n.to_string()),
+            data,
+        });
+
+        state.next_sequence += 1;
+
+        if state.history.len() == state.history_size {
+            state.history.pop_front();
+        }
+
+        if state.history_size > 0 {
+            state.history.push_back(published.clone());
+        }
+
+        // Subscribers that have gone away, or are not keeping up, are dropped here.
+        // Their receivers see the hub has gone once they have read what was already sent.
+        state.subscribers.retain(|s| s.try_send(published.clone()).is_ok());
+    }
+
+    /// Subscribe to new events. Events after `last_sequence` that are still in the history
+    /// are returned so nothing is missed between them and the first received event.
+    pub fn subscribe(&self, last_sequence: Option<u64>) -> (Vec<Arc<PublishedEvent>>, Receiver<Arc<PublishedEvent>>) {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = sync_channel(state.subscriber_capacity);
+
+        let missed = match last_sequence {
+            Some(last) => state.history.iter().filter(|e| e.sequence > last).cloned().collect(),
+            None => Vec::new()
+        };
+
+        // The sender is dropped straight away, so the subscriber sees the hub is gone.
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+
+        (missed, receiver)
+    }
+
+    /// Drop every subscriber, ending their streams, and refuse new ones.
+    /// Events are still kept in the history.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.closed = true;
+        state.subscribers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::TryRecvError;
+    use uuid::Uuid;
+    use crate::common::{EventType, RunResultEvent};
+    use super::*;
+
+    fn publish(hub: &EventHub, count: usize) {
+        for i in 0..count {
+            hub.publish(&Event { id: Uuid::new_v4(), event_type: EventType::RunResult(RunResultEvent { successful: true, message: i.to_string() }) });
+        }
+    }
+
+    fn sequences(receiver: &Receiver<Arc<PublishedEvent>>) -> Vec<u64> {
+        receiver.try_iter().map(|e| e.sequence).collect()
+    }
+
+    #[test]
+    fn subscribers_falling_behind_are_dropped() {
+        let hub = EventHub::create(16, 2);
+        let (_, slow) = hub.subscribe(None);
+        let (_, keeping_up) = hub.subscribe(None);
+
+        publish(&hub, 2);
+        assert_eq!(sequences(&keeping_up), [1, 2]);
+
+        publish(&hub, 2);
+        assert_eq!(sequences(&keeping_up), [3, 4]);
+
+        // What was sent before it was dropped can still be read.
+        assert_eq!(sequences(&slow), [1, 2]);
+        assert!(matches!(slow.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn a_dropped_subscriber_catches_up_from_the_history() {
+        let hub = EventHub::create(16, 1);
+        let (_, slow) = hub.subscribe(None);
+
+        publish(&hub, 3);
+        assert_eq!(sequences(&slow), [1]);
+
+        let (missed, receiver) = hub.subscribe(Some(1));
+        assert_eq!(missed.iter().map(|e| e.sequence).collect::<Vec<_>>(), [2, 3]);
+
+        publish(&hub, 1);
+        assert_eq!(sequences(&receiver), [4]);
+    }
+
+    #[test]
+    fn close_ends_every_subscription() {
+        let hub = EventHub::create(16, 4);
+        let (_, before) = hub.subscribe(None);
+
+        hub.close();
+        let (_, after) = hub.subscribe(None);
+
+        assert!(matches!(before.try_recv(), Err(TryRecvError::Disconnected)));
+        assert!(matches!(after.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+}
@@ -1,34 +1,56 @@
 ﻿mod event_handler;
+pub mod hub;
 
-use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::thread::JoinHandle;
-use crate::{Action, Command, Log};
+use std::time::Instant;
+use crate::{Command, Log};
 use crate::common::Event;
+use crate::common::queue::{QueueReceiver, QueueSender};
+use crate::common::shutdown::{join_until, receive_until, ShutdownSignal};
 use crate::events::event_handler::handle_event;
+use crate::events::hub::EventHub;
 
 pub(crate) struct EventLoop {
-    sender: Sender<Event>,
+    sender: QueueSender<Event>,
     thread: JoinHandle<()>,
+    stopping: ShutdownSignal,
 }
 
 impl EventLoop {
-    pub fn start(command_sender: Sender<Command>, event_receiver: Receiver<Event>, event_sender: Sender<Event>, log: &Log) -> EventLoop {
+    pub fn start(command_sender: QueueSender<Command>, event_receiver: QueueReceiver<Event>, event_sender: QueueSender<Event>, hub: EventHub, log: &Log) -> EventLoop {
         let logger = log.get_logger("event-loop".to_string());
 
         logger.log_info("Starting".to_string()).unwrap();
         
-        let thread = thread::spawn(move || loop {
-            let event = event_receiver.recv().unwrap();
-            // Convert event to command(s).
-            let commands = handle_event(event, &logger);
-            // Send commands.
-            for command in commands {
-                command_sender.send(command).unwrap();
-            }
+        let stopping = ShutdownSignal::create();
+        let signal = stopping.clone();
+
+        let thread = thread::spawn(move || {
+            receive_until(&event_receiver, &signal, |event| {
+                // Let anyone watching know about the event before it is handled.
+                hub.publish(&event);
+                // Convert event to command(s).
+                let commands = handle_event(event, &logger);
+                // Send commands.
+                for command in commands {
+                    // Waiting for room could deadlock, the orchestrator may be waiting on results that raise events.
+                    if let Err(e) = command_sender.send(command) {
+                        logger.log_warning(format!("Command dropped - {}", e)).unwrap();
+                    }
+                }
+            });
+
+            logger.log_info("Stopped".to_string()).unwrap();
         });
 
-        EventLoop { sender: event_sender, thread }
+        EventLoop { sender: event_sender, thread, stopping }
+    }
+
+    /// Handle the events already raised, then stop.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.stopping.trigger();
+        join_until(self.thread, deadline)
     }
     
     pub fn raise_event(&self, event: Event) {
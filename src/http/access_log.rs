@@ -0,0 +1,188 @@
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use crate::Logger;
+use crate::http::error::HttpError;
+use crate::http::middleware::{Middleware, RequestInfo};
+use crate::http::router::RouteResult;
+
+/// The time format of the Common and Combined Log Formats, such as `10/Oct/2000:13:55:36 +0000`.
+const CLF_DATE_FORMAT: &str = "%d/%b/%Y:%H:%M:%S %z";
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`, followed by the latency in microseconds like Apache's `%D`.
+    Common,
+    /// Common with the `Referer` and `User-Agent` added before the latency.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Written in a config file as `"log"` or `{ "file": "access.log" }`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessLogTarget {
+    /// Through the logging subsystem, alongside everything else the controller logs.
+    Log,
+    /// Appended to a file of its own, one entry per line.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessLogSettings {
+    pub format: AccessLogFormat,
+    pub target: AccessLogTarget,
+}
+
+/// Writes an entry for every request that reaches the middleware chain.
+/// The latency is measured from the request having been read to its response being ready to send,
+/// which includes any time spent waiting for a worker. Sending the response, or a streamed body, is not included.
+pub(crate) struct AccessLogMiddleware {
+    format: AccessLogFormat,
+    writer: AccessLogWriter,
+}
+
+enum AccessLogWriter {
+    Log(Logger),
+    File(Mutex<LineWriter<File>>),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessLogEntry<'a> {
+    time: String,
+    remote_address: Option<&'a str>,
+    user: Option<&'a str>,
+    method: &'a str,
+    target: &'a str,
+    http_version: &'a str,
+    status: i16,
+    /// `None` for streamed bodies, whose size is not known when the entry is written.
+    bytes: Option<usize>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    request_id: Option<&'a str>,
+    duration_ms: f64,
+}
+
+impl AccessLogSettings {
+    pub fn create(format: AccessLogFormat, target: AccessLogTarget) -> AccessLogSettings {
+        AccessLogSettings { format, target }
+    }
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings::create(AccessLogFormat::Combined, AccessLogTarget::Log)
+    }
+}
+
+impl AccessLogMiddleware {
+    /// `logger` is written to if the target is the log. A file target is opened, or created, for appending.
+    pub fn create(settings: &AccessLogSettings, logger: Logger) -> Result<AccessLogMiddleware, HttpError> {
+        let writer = match &settings.target {
+            AccessLogTarget::Log => AccessLogWriter::Log(logger),
+            AccessLogTarget::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                AccessLogWriter::File(Mutex::new(LineWriter::new(file)))
+            }
+        };
+
+        Ok(AccessLogMiddleware { format: settings.format, writer })
+    }
+
+    fn format_entry(&self, request: &RequestInfo, result: &RouteResult) -> String {
+        let elapsed = request.started.elapsed();
+        let received = Utc::now() - chrono::Duration::from_std(elapsed).unwrap_or_else(|_| chrono::Duration::zero());
+
+        let response = &result.response;
+        let bytes = match response.stream {
+            Some(_) => None,
+            None => Some(response.body.as_ref().map(|b| b.len()).unwrap_or(0))
+        };
+
+        let user = request.principal.as_ref().map(|p| p.name.as_str());
+        let referer = request.headers.get("REFERER").map(|v| v.as_str());
+        let user_agent = request.headers.get("USER-AGENT").map(|v| v.as_str());
+
+        match self.format {
+            AccessLogFormat::Json => {
+                let entry = AccessLogEntry {
+                    time: received.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    remote_address: request.remote_address.as_deref(),
+                    user,
+                    method: request.verb.get_str(),
+                    target: &request.route,
+                    http_version: &request.http_version,
+                    status: response.header.status.get_code(),
+                    bytes,
+                    referer,
+                    user_agent,
+                    request_id: request.headers.get("X-REQUEST-ID").map(|v| v.as_str()),
+                    duration_ms: elapsed.as_secs_f64() * 1000.0,
+                };
+
+                serde_json::to_string(&entry).unwrap_or_default()
+            }
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let mut line = format!(
+                    "{} - {} [{}] \"{} {} {}\" {} {}",
+                    request.remote_address.as_deref().unwrap_or("-"),
+                    user.map(escape).unwrap_or_else(|| "-".to_string()),
+                    format_time(received),
+                    request.verb.get_str(),
+                    escape(&request.route),
+                    escape(&request.http_version),
+                    response.header.status.get_code(),
+                    // The formats write a body of nothing as `-`.
+                    bytes.filter(|b| *b > 0).map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()));
+
+                if self.format == AccessLogFormat::Combined {
+                    line.push_str(&format!(" \"{}\" \"{}\"", escape(referer.unwrap_or("-")), escape(user_agent.unwrap_or("-"))));
+                }
+
+                line.push_str(&format!(" {}", elapsed.as_micros()));
+                line
+            }
+        }
+    }
+}
+
+impl Middleware for AccessLogMiddleware {
+    fn after(&self, request: &RequestInfo, result: &mut RouteResult) {
+        let entry = self.format_entry(request, result);
+
+        match &self.writer {
+            AccessLogWriter::Log(logger) => logger.log_info(entry).unwrap(),
+            AccessLogWriter::File(file) => {
+                // A full disk should not take the api down with it.
+                let _ = writeln!(file.lock().unwrap(), "{}", entry);
+            }
+        }
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format(CLF_DATE_FORMAT).to_string()
+}
+
+/// Escape a value for a quoted field, so a client cannot break the line apart or forge another entry.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::common::{AuthMethod, Principal};
+use crate::common::permissions::AccessPolicy;
+use crate::http::common::{HttpRequest, HttpResponse, HttpStatus};
+use crate::http::error::HttpError;
+use crate::http::middleware::Middleware;
+use crate::http::router::RouteResult;
+
+/// Credentials accepted by the api, read from a json file such as:
+///
+/// ```json
+/// {
+///     "apiKeys": [ { "name": "dashboard", "key": "..." } ],
+///     "tokenSecret": "...",
+///     "disabled": [ "old-dashboard" ],
+///     "access": { ... }
+/// }
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// The HMAC-SHA256 secret bearer tokens are signed with, tokens are refused if not set.
+    /// Tokens are issued to api key holders by `POST /auth/token`.
+    pub token_secret: Option<String>,
+    /// Principals that are known but no longer allowed to use the api.
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    /// The roles and grants of each principal, see `AccessPolicy`. Any authenticated principal may do anything if `None`.
+    pub access: Option<AccessPolicy>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+}
+
+/// The signed part of a bearer token.
+#[derive(Deserialize, Serialize)]
+struct TokenClaims {
+    sub: String,
+    /// Expiry as seconds since the unix epoch.
+    exp: u64,
+}
+
+#[derive(Serialize)]
+struct AuthErrorBody {
+    error: &'static str,
+    message: &'static str,
+}
+
+/// Authenticates every request and records the principal on it.
+/// Credentials are sent as `Authorization: Bearer <token>`, `Authorization: ApiKey <key>` or `X-Api-Key: <key>`.
+pub(crate) struct AuthMiddleware {
+    config: AuthConfig,
+    /// Paths let through without credentials, such as a page that asks for them.
+    public_paths: Vec<String>,
+    /// Paths whose whole subtree is let through without credentials, such as static assets.
+    public_prefixes: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum AuthFailure {
+    /// No credentials, or ones that could not be verified.
+    Unauthorized(&'static str),
+    /// Valid credentials for a principal that is not allowed in.
+    Forbidden(&'static str),
+}
+
+impl AuthConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<AuthConfig, HttpError> {
+        let contents = fs::read(path)?;
+
+        serde_json::from_slice(&contents).map_err(|_| HttpError::Serialization("Invalid authentication config."))
+    }
+
+    /// Sign a token for `subject` that is valid for `valid_for`. `None` if there is no token secret.
+    pub fn issue_token(&self, subject: String, valid_for: Duration) -> Option<String> {
+        let secret = self.token_secret.as_ref()?;
+        let claims = TokenClaims { sub: subject, exp: unix_time() + valid_for.as_secs() };
+
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).ok()?);
+        let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload)?.finalize().into_bytes());
+
+        Some(format!("{}.{}", payload, signature))
+    }
+
+    fn authenticate(&self, request: &HttpRequest) -> Result<Principal, AuthFailure> {
+        let authorization = request.header.headers.get("AUTHORIZATION").map(|v| v.trim());
+
+        let principal = match (authorization, request.header.headers.get("X-API-KEY")) {
+            (Some(value), _) => match value.split_once(' ') {
+                Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => self.verify_token(token.trim())?,
+                Some((scheme, key)) if scheme.eq_ignore_ascii_case("ApiKey") => self.verify_key(key.trim())?,
+                _ => return Err(AuthFailure::Unauthorized("Unsupported authorization scheme."))
+            },
+            (None, Some(key)) => self.verify_key(key.trim())?,
+            (None, None) => return Err(AuthFailure::Unauthorized("Missing credentials."))
+        };
+
+        match self.disabled.contains(&principal.name) {
+            true => Err(AuthFailure::Forbidden("Principal is disabled.")),
+            false => Ok(principal)
+        }
+    }
+
+    fn verify_key(&self, key: &str) -> Result<Principal, AuthFailure> {
+        // Check every key so the time taken does not depend on which one matched.
+        let matched = self.api_keys
+            .iter()
+            .fold(None, |found, k| match constant_time_eq(k.key.as_bytes(), key.as_bytes()) {
+                true => Some(k),
+                false => found
+            });
+
+        match matched {
+            Some(k) => Ok(Principal { name: k.name.clone(), method: AuthMethod::ApiKey }),
+            None => Err(AuthFailure::Unauthorized("Invalid api key."))
+        }
+    }
+
+    fn verify_token(&self, token: &str) -> Result<Principal, AuthFailure> {
+        let invalid = AuthFailure::Unauthorized("Invalid bearer token.");
+
+        let secret = match &self.token_secret {
+            Some(secret) => secret,
+            None => return Err(invalid)
+        };
+
+        let (payload, signature) = token.split_once('.').ok_or(invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid)?;
+
+        match sign(secret, payload).map(|mac| mac.verify_slice(&signature)) {
+            Some(Ok(_)) => {}
+            _ => return Err(invalid)
+        }
+
+        let claims: TokenClaims = URL_SAFE_NO_PAD.decode(payload)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(invalid)?;
+
+        match claims.exp > unix_time() {
+            true => Ok(Principal { name: claims.sub, method: AuthMethod::BearerToken }),
+            false => Err(AuthFailure::Unauthorized("Bearer token has expired."))
+        }
+    }
+}
+
+impl AuthMiddleware {
+    pub fn create(config: AuthConfig) -> AuthMiddleware {
+        AuthMiddleware { config, public_paths: Vec::new(), public_prefixes: Vec::new() }
+    }
+
+    /// Let requests for exactly `path` through without credentials.
+    pub fn allow_anonymous(&mut self, path: &str) {
+        self.public_paths.push(path.to_string());
+    }
+
+    /// Let requests for `prefix` and every path below it through without credentials.
+    /// `/ui` covers `/ui` and `/ui/app.js`, but not `/uidata`.
+    pub fn allow_anonymous_under(&mut self, prefix: &str) {
+        self.public_prefixes.push(prefix.trim_end_matches('/').to_string());
+    }
+
+    fn is_public(&self, path: &str) -> bool {
+        self.public_paths.iter().any(|p| p == path)
+            || self.public_prefixes.iter().any(|p| path.strip_prefix(p.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn before(&self, request: &mut HttpRequest) -> Option<RouteResult> {
+        if self.is_public(&request.header.url.path) {
+            return None;
+        }
+
+        match self.config.authenticate(request) {
+            Ok(principal) => {
+                request.principal = Some(principal);
+                None
+            }
+            Err(AuthFailure::Unauthorized(message)) => {
+                let mut headers = HashMap::new();
+                headers.insert("WWW-Authenticate".to_string(), "Bearer".to_string());
+
+                Some(error_result(HttpStatus::Unauthorized, "unauthorized", message, headers))
+            }
+            Err(AuthFailure::Forbidden(message)) => {
+                Some(error_result(HttpStatus::Forbidden, "forbidden", message, HashMap::new()))
+            }
+        }
+    }
+}
+
+pub(crate) fn error_result(status: HttpStatus, error: &'static str, message: &'static str, headers: HashMap<String, String>) -> RouteResult {
+    let body = serde_json::to_vec(&AuthErrorBody { error, message }).ok();
+
+    RouteResult::create(HttpResponse::create(status, "application/json".to_string(), headers, body))
+}
+
+fn sign(secret: &str, payload: &str) -> Option<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(payload.as_bytes());
+
+    Some(mac)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::http::common::HttpRequestHeader;
+    use super::*;
+
+    fn config() -> AuthConfig {
+        serde_json::from_str(r#"{
+            "apiKeys": [ { "name": "dashboard", "key": "dash-key" }, { "name": "old-dashboard", "key": "old-key" } ],
+            "tokenSecret": "secret",
+            "disabled": [ "old-dashboard" ]
+        }"#).unwrap()
+    }
+
+    fn request(path: &str, headers: &[&str]) -> HttpRequest {
+        let mut lines = vec![format!("GET {} HTTP/1.1", path)];
+        lines.extend(headers.iter().map(|h| h.to_string()));
+
+        HttpRequest {
+            header: HttpRequestHeader::parse_from_string(lines.join("\r\n")).unwrap(),
+            body: None,
+            remote_address: None,
+            principal: None,
+            received: Instant::now(),
+        }
+    }
+
+    /// A token for any claims, signed with `secret`.
+    fn signed_token(secret: &str, sub: &str, exp: u64) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&TokenClaims { sub: sub.to_string(), exp }).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload).unwrap().finalize().into_bytes());
+
+        format!("{}.{}", payload, signature)
+    }
+
+    fn authenticate(config: &AuthConfig, header: &str) -> Result<Principal, AuthFailure> {
+        config.authenticate(&request("/nodes", &[header]))
+    }
+
+    #[test]
+    fn issued_tokens_verify_until_they_expire() {
+        let config = config();
+        let token = config.issue_token("dashboard".to_string(), Duration::from_secs(60)).unwrap();
+
+        let principal = config.verify_token(&token).ok().unwrap();
+        assert_eq!(principal.name, "dashboard");
+        assert!(principal.method == AuthMethod::BearerToken);
+
+        let expired = signed_token("secret", "dashboard", unix_time() - 1);
+        assert!(matches!(config.verify_token(&expired), Err(AuthFailure::Unauthorized("Bearer token has expired."))));
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let config = config();
+        let token = config.issue_token("dashboard".to_string(), Duration::from_secs(60)).unwrap();
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // Claims changed without signing them again.
+        let other_claims = signed_token("secret", "admin", unix_time() + 60);
+        let forged = format!("{}.{}", other_claims.split_once('.').unwrap().0, signature);
+
+        // A different signature, of the same length.
+        let mut flipped = signature.as_bytes().to_vec();
+        flipped[0] = if flipped[0] == b'A' { b'B' } else { b'A' };
+        let flipped = format!("{}.{}", payload, String::from_utf8(flipped).unwrap());
+
+        let invalid = [
+            forged,
+            flipped,
+            signed_token("other secret", "dashboard", unix_time() + 60),
+            format!("{}.", payload),
+            payload.to_string(),
+            format!("{}.not base64!", payload),
+        ];
+
+        for token in invalid {
+            assert!(matches!(config.verify_token(&token), Err(AuthFailure::Unauthorized("Invalid bearer token."))), "{}", token);
+        }
+    }
+
+    #[test]
+    fn tokens_need_a_secret() {
+        let config = AuthConfig { token_secret: None, ..config() };
+        let token = signed_token("secret", "dashboard", unix_time() + 60);
+
+        assert!(config.issue_token("dashboard".to_string(), Duration::from_secs(60)).is_none());
+        assert!(config.verify_token(&token).is_err());
+    }
+
+    #[test]
+    fn each_scheme_is_accepted() {
+        let config = config();
+        let token = config.issue_token("dashboard".to_string(), Duration::from_secs(60)).unwrap();
+
+        for header in ["Authorization: ApiKey dash-key", "Authorization: apikey  dash-key", "X-Api-Key: dash-key"] {
+            let principal = authenticate(&config, header).ok().unwrap();
+            assert!(principal.name == "dashboard" && principal.method == AuthMethod::ApiKey, "{}", header);
+        }
+
+        for header in [format!("Authorization: Bearer {}", token), format!("Authorization: bearer {}", token)] {
+            let principal = authenticate(&config, &header).ok().unwrap();
+            assert!(principal.name == "dashboard" && principal.method == AuthMethod::BearerToken, "{}", header);
+        }
+    }
+
+    #[test]
+    fn bad_credentials_are_unauthorized() {
+        let config = config();
+
+        assert!(matches!(authenticate(&config, "Authorization: ApiKey wrong"), Err(AuthFailure::Unauthorized("Invalid api key."))));
+        assert!(matches!(authenticate(&config, "X-Api-Key: dash-ke"), Err(AuthFailure::Unauthorized("Invalid api key."))));
+        assert!(matches!(authenticate(&config, "Authorization: Basic ZGFzaDprZXk="), Err(AuthFailure::Unauthorized("Unsupported authorization scheme."))));
+        assert!(matches!(authenticate(&config, "Authorization: dash-key"), Err(AuthFailure::Unauthorized("Unsupported authorization scheme."))));
+        assert!(matches!(config.authenticate(&request("/nodes", &[])), Err(AuthFailure::Unauthorized("Missing credentials."))));
+    }
+
+    #[test]
+    fn disabled_principals_are_forbidden() {
+        let config = config();
+        let token = signed_token("secret", "old-dashboard", unix_time() + 60);
+
+        assert!(matches!(authenticate(&config, "X-Api-Key: old-key"), Err(AuthFailure::Forbidden(_))));
+        assert!(matches!(authenticate(&config, &format!("Authorization: Bearer {}", token)), Err(AuthFailure::Forbidden(_))));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_keys() {
+        assert!(constant_time_eq(b"dash-key", b"dash-key"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"dash-key", b"dash-kez"));
+        assert!(!constant_time_eq(b"xash-key", b"dash-key"));
+        assert!(!constant_time_eq(b"dash-key", b"dash-key2"));
+        assert!(!constant_time_eq(b"dash", b"dash-key"));
+    }
+
+    #[test]
+    fn middleware_answers_401_and_403_and_lets_public_paths_through() {
+        let mut middleware = AuthMiddleware::create(config());
+        middleware.allow_anonymous("/login");
+        middleware.allow_anonymous_under("/ui/");
+
+        let mut allowed = request("/nodes", &["X-Api-Key: dash-key"]);
+        assert!(middleware.before(&mut allowed).is_none());
+        assert_eq!(allowed.principal.unwrap().name, "dashboard");
+
+        let unauthorized = middleware.before(&mut request("/nodes", &[])).unwrap();
+        assert_eq!(unauthorized.response.header.status, HttpStatus::Unauthorized);
+        assert_eq!(unauthorized.response.header.headers["WWW-Authenticate"], "Bearer");
+
+        let forbidden = middleware.before(&mut request("/nodes", &["X-Api-Key: old-key"])).unwrap();
+        assert_eq!(forbidden.response.header.status, HttpStatus::Forbidden);
+
+        for path in ["/login", "/ui", "/ui/app.js"] {
+            assert!(middleware.before(&mut request(path, &[])).is_none(), "{}", path);
+        }
+
+        for path in ["/login/other", "/uidata"] {
+            assert!(middleware.before(&mut request(path, &[])).is_some(), "{}", path);
+        }
+    }
+}
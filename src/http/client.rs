@@ -1,20 +1,88 @@
 ﻿use std::collections::HashMap;
-use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
-use std::time::Duration;
-use crate::http::common::{HttpRequest, HttpResponse, HttpVerb};
-use crate::{Log, Logger};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rustls::ClientConfig;
+use crate::http::common::{DEFAULT_MAX_BODY_SIZE, HttpRequest, HttpResponse, HttpVerb};
+use crate::http::compression::{decompress_response, ACCEPT_ENCODING};
+use crate::http::error::HttpError;
+use crate::http::tls::{NetworkStream, TlsClientSettings};
 
 pub struct HttpClient {
     address: String,
+    max_body_size: usize,
+    pool: Option<HttpConnectionPool>,
+    timeouts: HttpClientTimeouts,
+    /// Connect over TLS with this configuration, plain TCP if `None`.
+    tls: Option<Arc<ClientConfig>>,
     //stream: TcpStream,
 }
 
+#[derive(Clone, Copy)]
+pub struct HttpClientTimeouts {
+    pub connect: Duration,
+    /// The longest a single read can wait for data.
+    pub read: Duration,
+    pub write: Duration,
+    /// The limit for the whole request, from connecting to the last byte of the response.
+    pub request: Duration,
+}
+
+
+/// Idle keep-alive connections, grouped by address. Clones share the same connections
+/// so a pool can be handed to each client that is created.
+#[derive(Clone)]
+pub struct HttpConnectionPool {
+    connections: Arc<Mutex<HashMap<String, Vec<PooledConnection>>>>,
+    max_per_address: usize,
+    max_total: usize,
+    idle_timeout: Duration,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+struct PooledConnection {
+    stream: BufReader<ClientStream>,
+    idle_since: Instant,
+}
+
+/// A connection that applies the client's timeouts to every read and write.
+struct ClientStream {
+    stream: NetworkStream,
+    timeouts: HttpClientTimeouts,
+    deadline: Instant,
+}
+
+enum ExchangeError {
+    /// The request could not be written, so the server cannot have acted on it.
+    NotSent,
+    /// The request was sent but nothing came back. The server may have acted on it,
+    /// so it is only safe to send again if the verb is idempotent.
+    NoResponse,
+    Failed(HttpError),
+}
+
 impl HttpClient {
     pub fn create(address: String) -> HttpClient {
-        HttpClient { address }
+        HttpClient { address, max_body_size: DEFAULT_MAX_BODY_SIZE, pool: None, timeouts: HttpClientTimeouts::default(), tls: None }
+    }
+
+    /// Create a client that reuses connections from `pool` and returns them after each request.
+    /// The client connects over TLS if the pool has been set up for it.
+    pub fn with_pool(address: String, pool: HttpConnectionPool) -> HttpClient {
+        let tls = pool.tls.clone();
+        HttpClient { address, max_body_size: DEFAULT_MAX_BODY_SIZE, pool: Some(pool), timeouts: HttpClientTimeouts::default(), tls }
+    }
+
+    /// Set the largest response body the client will accept.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
     }
-    
+
+    pub fn set_timeouts(&mut self, timeouts: HttpClientTimeouts) {
+        self.timeouts = timeouts;
+    }
+
     /*
     pub fn connect(address: String) -> Result<HttpClient, &'static str> {
         match TcpStream::connect(address) {
@@ -27,21 +95,391 @@ impl HttpClient {
         }
     }*/
 
-    pub fn get(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>) -> Result<HttpResponse, &'static str> {
-        match TcpStream::connect(self.address.clone()) {
-            Ok(mut stream) => {
-                let mut request = HttpRequest::create(route, HttpVerb::GET, content_type, addition_header, None);
+    pub fn get(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Get, content_type, addition_header, None))
+    }
 
-                match stream.write(&*request.to_bytes()) {
-                    Ok(_) => {
-                        HttpResponse::from_stream(&stream)
-                    }
-                    Err(_) => Err("Could not connect to server, GET request failed.")
+    pub fn head(&mut self, route: String, addition_header: HashMap<String, String>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Head, "text/plain".to_string(), addition_header, None))
+    }
+
+    pub fn post(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>, body: Vec<u8>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Post, content_type, addition_header, Some(body)))
+    }
+
+    pub fn put(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>, body: Vec<u8>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Put, content_type, addition_header, Some(body)))
+    }
+
+    pub fn patch(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>, body: Vec<u8>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Patch, content_type, addition_header, Some(body)))
+    }
+
+    pub fn delete(&mut self, route: String, content_type: String, addition_header: HashMap<String, String>) -> Result<HttpResponse, HttpError> {
+        self.request(HttpRequest::create(route, HttpVerb::Delete, content_type, addition_header, None))
+    }
+
+    /// Send any request, such as one made with `HttpRequest::builder`.
+    pub fn request(&mut self, mut request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let connection = match self.pool.is_some() {
+            true => "keep-alive",
+            false => "close"
+        };
+
+        request.header.headers.insert("Connection".to_string(), connection.to_string());
+        request.header.headers.entry("Host".to_string()).or_insert_with(|| self.address.clone());
+        request.header.headers.entry("Accept-Encoding".to_string()).or_insert_with(|| ACCEPT_ENCODING.to_string());
+
+        let verb = request.header.verb;
+        let bytes = request.to_bytes();
+        let deadline = Instant::now() + self.timeouts.request;
+
+        // A pooled connection may have been closed by the server since it was last used,
+        // if so try once more on a fresh connection.
+        if let Some(mut stream) = self.pool.as_ref().and_then(|p| p.take(&self.address)) {
+            stream.get_mut().start_request(self.timeouts, deadline);
+
+            match self.exchange(stream, &bytes, verb) {
+                Ok(response) => return Ok(response),
+                Err(ExchangeError::NotSent) => {}
+                Err(ExchangeError::NoResponse) if verb.is_idempotent() => {}
+                Err(ExchangeError::NoResponse) => return Err(HttpError::Connection("Connection closed before a response was received.")),
+                Err(ExchangeError::Failed(error)) => return Err(error)
+            }
+        }
+
+        let stream = BufReader::new(self.connect(deadline)?);
+
+        match self.exchange(stream, &bytes, verb) {
+            Ok(response) => Ok(response),
+            Err(ExchangeError::NotSent) => Err(HttpError::Connection("Could not send request to server.")),
+            Err(ExchangeError::NoResponse) => Err(HttpError::Connection("Could not connect to server, no response received.")),
+            Err(ExchangeError::Failed(error)) => Err(error)
+        }
+    }
+
+    fn connect(&self, deadline: Instant) -> Result<ClientStream, HttpError> {
+        let addresses = match self.address.to_socket_addrs() {
+            Ok(addresses) => addresses,
+            Err(_) => return Err(HttpError::Connection("Could not resolve server address."))
+        };
+
+        let mut result = Err(HttpError::Connection("Could not resolve server address."));
+
+        for address in addresses {
+            let timeout = self.timeouts.connect.min(deadline.saturating_duration_since(Instant::now()));
+
+            if timeout.is_zero() {
+                return Err(HttpError::Timeout);
+            }
+
+            result = match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => {
+                    let stream = NetworkStream::connect(stream, &self.address, self.tls.as_ref())?;
+                    let stream = ClientStream::create(stream, self.timeouts, deadline);
+
+                    // The TLS handshake reads and writes on the first write, both need a timeout before then.
+                    stream.set_timeouts()?;
+
+                    return Ok(stream);
                 }
+                Err(e) if is_timeout(&e) => Err(HttpError::Timeout),
+                Err(_) => Err(HttpError::Connection("Could not connect to server."))
+            };
+        }
+
+        result
+    }
+
+    fn exchange(&mut self, mut stream: BufReader<ClientStream>, request: &[u8], verb: HttpVerb) -> Result<HttpResponse, ExchangeError> {
+        match stream.get_mut().write_all(request) {
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => return Err(ExchangeError::Failed(HttpError::Timeout)),
+            Err(_) => return Err(ExchangeError::NotSent)
+        }
+
+        match stream.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => {}
+            Err(e) if is_timeout(&e) => return Err(ExchangeError::Failed(HttpError::Timeout)),
+            _ => return Err(ExchangeError::NoResponse)
+        }
+
+        let mut response = match verb {
+            HttpVerb::Head => HttpResponse::header_only_from_stream(&mut stream),
+            _ => HttpResponse::from_stream(&mut stream, self.max_body_size)
+        }.map_err(ExchangeError::Failed)?;
+
+        if let Some(pool) = &self.pool {
+            if can_reuse(&response) {
+                pool.release(&self.address, stream);
             }
-            Err(_) => {
-                Err("Could not connect to server.")
+        }
+
+        // The connection is done with, a body that cannot be decoded does not stop it being reused.
+        decompress_response(&mut response, self.max_body_size).map_err(ExchangeError::Failed)?;
+
+        Ok(response)
+    }
+}
+
+impl Default for HttpClientTimeouts {
+    fn default() -> Self {
+        HttpClientTimeouts {
+            connect: Duration::from_secs(3),
+            read: Duration::from_secs(5),
+            write: Duration::from_secs(5),
+            request: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ClientStream {
+    fn create(stream: NetworkStream, timeouts: HttpClientTimeouts, deadline: Instant) -> ClientStream {
+        ClientStream { stream, timeouts, deadline }
+    }
+
+    /// Reset the timeouts when a pooled connection is reused for a new request.
+    fn start_request(&mut self, timeouts: HttpClientTimeouts, deadline: Instant) {
+        self.timeouts = timeouts;
+        self.deadline = deadline;
+    }
+
+    /// Limit the next read and write on the socket. Both are set for either, as a TLS stream
+    /// may need to write to read, such as during the handshake, or read to write.
+    fn set_timeouts(&self) -> std::io::Result<()> {
+        let tcp = self.stream.tcp();
+
+        tcp.set_read_timeout(Some(self.remaining(self.timeouts.read)?))?;
+        tcp.set_write_timeout(Some(self.remaining(self.timeouts.write)?))
+    }
+
+    /// The time an operation may wait, the shorter of its own timeout and what is left of the request.
+    fn remaining(&self, timeout: Duration) -> std::io::Result<Duration> {
+        let remaining = timeout.min(self.deadline.saturating_duration_since(Instant::now()));
+
+        match remaining.is_zero() {
+            true => Err(std::io::Error::new(ErrorKind::TimedOut, "Request timed out.")),
+            false => Ok(remaining)
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.set_timeouts()?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.set_timeouts()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl HttpConnectionPool {
+    pub fn create(max_per_address: usize, max_total: usize, idle_timeout: Duration) -> HttpConnectionPool {
+        HttpConnectionPool {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            max_per_address,
+            max_total,
+            idle_timeout,
+            tls: None,
+        }
+    }
+
+    /// Make clients created with this pool connect over TLS. Set this before the pool is cloned.
+    pub fn set_tls(&mut self, settings: &TlsClientSettings) {
+        self.tls = Some(settings.build());
+    }
+
+    /// Take the most recently used live connection to `address`, discarding any that are stale.
+    fn take(&self, address: &str) -> Option<BufReader<ClientStream>> {
+        let mut connections = self.connections.lock().unwrap();
+        let idle = connections.get_mut(address)?;
+
+        while let Some(connection) = idle.pop() {
+            if connection.idle_since.elapsed() < self.idle_timeout && !is_stale(&connection.stream) {
+                return Some(connection.stream);
             }
         }
+
+        None
     }
-}
\ No newline at end of file
+
+    /// Return a connection to the pool. It is closed instead if the pool is full.
+    fn release(&self, address: &str, stream: BufReader<ClientStream>) {
+        let mut connections = self.connections.lock().unwrap();
+
+        // Drop anything that has been idle too long before counting.
+        for idle in connections.values_mut() {
+            idle.retain(|c| c.idle_since.elapsed() < self.idle_timeout);
+        }
+
+        let total: usize = connections.values().map(|idle| idle.len()).sum();
+        let idle = connections.entry(address.to_string()).or_default();
+
+        if idle.len() < self.max_per_address && total < self.max_total {
+            idle.push(PooledConnection { stream, idle_since: Instant::now() });
+        }
+    }
+}
+
+/// Check if the server has closed an idle connection (or sent something unexpected on it).
+fn is_stale(stream: &BufReader<ClientStream>) -> bool {
+    if !stream.buffer().is_empty() {
+        return true;
+    }
+
+    let tcp = stream.get_ref().stream.tcp();
+
+    if tcp.set_nonblocking(true).is_err() {
+        return true;
+    }
+
+    let mut probe = [0; 1];
+    let stale = !matches!(tcp.peek(&mut probe), Err(e) if e.kind() == ErrorKind::WouldBlock);
+
+    tcp.set_nonblocking(false).is_err() || stale
+}
+
+fn can_reuse(response: &HttpResponse) -> bool {
+    let connection = response.header.headers.get("CONNECTION").map(|v| v.to_lowercase());
+
+    match (response.header.http_version.as_str(), connection) {
+        (_, Some(c)) if c.contains("close") => false,
+        ("HTTP/1.1", _) => true,
+        (_, Some(c)) => c.contains("keep-alive"),
+        (_, None) => false
+    }
+}
+
+fn is_timeout(error: &std::io::Error) -> bool {
+    error.kind() == ErrorKind::TimedOut || error.kind() == ErrorKind::WouldBlock
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+    use super::*;
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+
+    /// Answer `count` connections with `reply`, returning each request as it was received.
+    fn serve(count: usize, reply: &'static str) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            (0..count).map(|_| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let request = read_request(&mut reader);
+
+                reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                request
+            }).collect()
+        });
+
+        (address, handle)
+    }
+
+    fn read_request(reader: &mut BufReader<TcpStream>) -> String {
+        let mut request = String::new();
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            if let Some((key, value)) = line.split_once(':') {
+                if key.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+
+            request.push_str(&line);
+
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+        request
+    }
+
+    fn body(response: &HttpResponse) -> &str {
+        std::str::from_utf8(response.body.as_deref().unwrap_or_default()).unwrap()
+    }
+
+    #[test]
+    fn each_verb_sends_its_method_and_body() {
+        let (address, server) = serve(6, OK);
+        let mut client = HttpClient::create(address);
+        let json = "application/json".to_string();
+
+        assert_eq!(body(&client.get("/a".to_string(), "text/plain".to_string(), HashMap::new()).unwrap()), "ok");
+        assert!(client.head("/b".to_string(), HashMap::new()).unwrap().body.is_none());
+        assert_eq!(body(&client.post("/c".to_string(), json.clone(), HashMap::new(), b"{\"p\":1}".to_vec()).unwrap()), "ok");
+        assert_eq!(body(&client.put("/d".to_string(), json.clone(), HashMap::new(), b"{\"u\":2}".to_vec()).unwrap()), "ok");
+        assert_eq!(body(&client.patch("/e".to_string(), json.clone(), HashMap::new(), b"{\"m\":3}".to_vec()).unwrap()), "ok");
+        assert_eq!(body(&client.delete("/f".to_string(), json, HashMap::new()).unwrap()), "ok");
+
+        let requests = server.join().unwrap();
+
+        assert!(requests[0].starts_with("GET /a HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("HEAD /b HTTP/1.1\r\n"));
+        assert!(requests[2].starts_with("POST /c HTTP/1.1\r\n") && requests[2].ends_with("\r\n\r\n{\"p\":1}"));
+        assert!(requests[3].starts_with("PUT /d HTTP/1.1\r\n") && requests[3].ends_with("\r\n\r\n{\"u\":2}"));
+        assert!(requests[4].starts_with("PATCH /e HTTP/1.1\r\n") && requests[4].ends_with("\r\n\r\n{\"m\":3}"));
+        assert!(requests[5].starts_with("DELETE /f HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn query_parameters_are_encoded() {
+        let (address, server) = serve(1, OK);
+        let mut client = HttpClient::create(address);
+        let request = HttpRequest::builder(HttpVerb::Get, "/nodes?all=true")
+            .query("name", "living room/1")
+            .query("a&b", "c=d")
+            .build();
+
+        client.request(request).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /nodes?all=true&name=living%20room%2F1&a%26b=c%3Dd HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn body_over_max_size_is_refused() {
+        let (address, server) = serve(1, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123456789");
+        let mut client = HttpClient::create(address);
+        client.set_max_body_size(4);
+
+        assert!(client.get("/".to_string(), "text/plain".to_string(), HashMap::new()).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = HttpClient::create(listener.local_addr().unwrap().to_string());
+        client.set_timeouts(HttpClientTimeouts { read: Duration::from_millis(100), ..HttpClientTimeouts::default() });
+
+        // Connections are accepted by the listener's backlog, nothing is ever sent back.
+        let started = Instant::now();
+        let result = client.get("/".to_string(), "text/plain".to_string(), HashMap::new());
+
+        assert!(matches!(result, Err(HttpError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -1,40 +1,151 @@
 ﻿use std::collections::HashMap;
-use std::io::Read;
-use std::net::TcpStream;
-use std::num::ParseIntError;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+use serde::Serialize;
+use crate::http::error::HttpError;
+use crate::http::url::{encode_component, Url};
 use crate::Logger;
+use crate::common::Principal;
+use std::time::Instant;
 
-#[derive(Clone, Copy)]
+/// The largest header block (request/status line and headers) that will be read.
+pub const MAX_HEADER_SIZE: usize = 4096;
+
+/// The header count limit used when none is configured.
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 64;
+
+/// The body size limit used when none is configured.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How much a client may send in one request.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+    /// The request line and headers together, in bytes.
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+    pub max_body_size: usize,
+}
+
+pub type BodyStream = Box<dyn Iterator<Item = Vec<u8>> + Send + 'static>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum HttpVerb {
-    GET,
-    HEAD,
-    POST,
-    PUT,
-    DELETE,
-    CONNECT,
-    OPTIONS,
-    TRACE,
-    PATCH,
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub enum HttpStatus {
+    // 1xx informational
+    Continue,
+    SwitchingProtocols,
+    Processing,
+    EarlyHints,
+
+    // 2xx success
     Ok,
+    Created,
+    Accepted,
+    NonAuthoritativeInformation,
+    NoContent,
+    ResetContent,
+    PartialContent,
+    MultiStatus,
+    AlreadyReported,
+    ImUsed,
+
+    // 3xx redirection
+    MultipleChoices,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    UseProxy,
+    TemporaryRedirect,
+    PermanentRedirect,
+
+    // 4xx client error
     BadRequest,
     Unauthorized,
+    PaymentRequired,
+    Forbidden,
     NotFound,
     MethodNotAllowed,
+    NotAcceptable,
+    ProxyAuthenticationRequired,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    ImATeapot,
+    MisdirectedRequest,
+    UnprocessableEntity,
+    Locked,
+    FailedDependency,
+    TooEarly,
+    UpgradeRequired,
+    PreconditionRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    UnavailableForLegalReasons,
+
+    // 5xx server error
     InternalError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+    VariantAlsoNegotiates,
+    InsufficientStorage,
+    LoopDetected,
+    NotExtended,
+    NetworkAuthenticationRequired,
+    /// A status code not in the standard registry, with the reason phrase it was sent with.
+    Other(i16, String),
 }
 
 pub struct HttpRequest {
     pub header: HttpRequestHeader,
     pub body: Option<Vec<u8>>,
+    /// The address of the client that sent the request, set by the server.
+    pub remote_address: Option<String>,
+    /// Who sent the request, set once it has been authenticated.
+    pub principal: Option<Principal>,
+    /// When the request had been read, so the time it waits for a worker is counted in its latency.
+    pub received: Instant,
+}
+
+/// Builds a `HttpRequest` piece by piece, for requests with a body, query parameters or extra headers.
+pub struct HttpRequestBuilder {
+    verb: HttpVerb,
+    route: String,
+    content_type: String,
+    headers: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
 }
 
 pub struct HttpRequestHeader {
     pub route: String,
+    /// The route parsed into its decoded path, query and fragment.
+    pub url: Url,
     pub verb: HttpVerb,
     pub content_length: usize,
+    pub chunked: bool,
     pub headers: HashMap<String, String>,
     pub http_version: String,
 }
@@ -42,81 +153,272 @@ pub struct HttpRequestHeader {
 pub struct HttpResponse {
     pub header: HttpResponseHeader,
     pub body: Option<Vec<u8>>,
+    /// Chunks of a body of unknown length, written with chunked transfer-encoding.
+    pub stream: Option<BodyStream>,
 }
 
 pub struct HttpResponseHeader {
     pub http_version: String,
     pub status: HttpStatus,
     pub content_length: usize,
+    pub chunked: bool,
     //pub content_type: String,
     pub headers: HashMap<String, String>,
 }
 
-impl HttpVerb {
-    pub fn from_str(data: &str) -> Result<HttpVerb, &'static str> {
+impl FromStr for HttpVerb {
+    type Err = HttpError;
+
+    fn from_str(data: &str) -> Result<HttpVerb, HttpError> {
         match data.to_uppercase().as_str() {
-            "GET" => Ok(HttpVerb::GET),
-            "HEAD" => Ok(HttpVerb::HEAD),
-            "POST" => Ok(HttpVerb::POST),
-            "PUT" => Ok(HttpVerb::PUT),
-            "DELETE" => Ok(HttpVerb::DELETE),
-            "CONNECT" => Ok(HttpVerb::CONNECT),
-            "PATCH" => Ok(HttpVerb::PATCH),
-            "OPTIONS" => Ok(HttpVerb::OPTIONS),
-            "TRACE" => Ok(HttpVerb::TRACE),
-            _ => Err("Unknown http verb")
+            "GET" => Ok(HttpVerb::Get),
+            "HEAD" => Ok(HttpVerb::Head),
+            "POST" => Ok(HttpVerb::Post),
+            "PUT" => Ok(HttpVerb::Put),
+            "DELETE" => Ok(HttpVerb::Delete),
+            "CONNECT" => Ok(HttpVerb::Connect),
+            "PATCH" => Ok(HttpVerb::Patch),
+            "OPTIONS" => Ok(HttpVerb::Options),
+            "TRACE" => Ok(HttpVerb::Trace),
+            _ => Err(HttpError::UnsupportedVerb(data.to_string()))
         }
     }
+}
 
+impl HttpVerb {
     pub fn get_str(&self) -> &'static str {
         match self {
-            HttpVerb::GET => "GET",
-            HttpVerb::HEAD => "HEAD",
-            HttpVerb::POST => "POST",
-            HttpVerb::PUT => "PUT",
-            HttpVerb::DELETE => "DELETE",
-            HttpVerb::CONNECT => "CONNECT",
-            HttpVerb::OPTIONS => "OPTIONS",
-            HttpVerb::TRACE => "TRACE",
-            HttpVerb::PATCH => "PATCH"
+            HttpVerb::Get => "GET",
+            HttpVerb::Head => "HEAD",
+            HttpVerb::Post => "POST",
+            HttpVerb::Put => "PUT",
+            HttpVerb::Delete => "DELETE",
+            HttpVerb::Connect => "CONNECT",
+            HttpVerb::Options => "OPTIONS",
+            HttpVerb::Trace => "TRACE",
+            HttpVerb::Patch => "PATCH"
+        }
+    }
+
+    /// Check if sending the request more than once has the same effect as sending it once (RFC 9110 9.2.2).
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            HttpVerb::Get | HttpVerb::Head | HttpVerb::Put | HttpVerb::Delete | HttpVerb::Options | HttpVerb::Trace => true,
+            HttpVerb::Post | HttpVerb::Connect | HttpVerb::Patch => false
         }
     }
 }
 
 impl HttpStatus {
-    pub fn from_code(code: i16) -> Result<HttpStatus, &'static str> {
+    /// Map a status code to its status. Unknown codes keep the code and `reason` so they can be passed on.
+    pub fn from_code_and_reason(code: i16, reason: &str) -> Result<HttpStatus, HttpError> {
         match code {
+            100 => Ok(HttpStatus::Continue),
+            101 => Ok(HttpStatus::SwitchingProtocols),
+            102 => Ok(HttpStatus::Processing),
+            103 => Ok(HttpStatus::EarlyHints),
             200 => Ok(HttpStatus::Ok),
+            201 => Ok(HttpStatus::Created),
+            202 => Ok(HttpStatus::Accepted),
+            203 => Ok(HttpStatus::NonAuthoritativeInformation),
+            204 => Ok(HttpStatus::NoContent),
+            205 => Ok(HttpStatus::ResetContent),
+            206 => Ok(HttpStatus::PartialContent),
+            207 => Ok(HttpStatus::MultiStatus),
+            208 => Ok(HttpStatus::AlreadyReported),
+            226 => Ok(HttpStatus::ImUsed),
+            300 => Ok(HttpStatus::MultipleChoices),
+            301 => Ok(HttpStatus::MovedPermanently),
+            302 => Ok(HttpStatus::Found),
+            303 => Ok(HttpStatus::SeeOther),
+            304 => Ok(HttpStatus::NotModified),
+            305 => Ok(HttpStatus::UseProxy),
+            307 => Ok(HttpStatus::TemporaryRedirect),
+            308 => Ok(HttpStatus::PermanentRedirect),
             400 => Ok(HttpStatus::BadRequest),
             401 => Ok(HttpStatus::Unauthorized),
+            402 => Ok(HttpStatus::PaymentRequired),
+            403 => Ok(HttpStatus::Forbidden),
             404 => Ok(HttpStatus::NotFound),
             405 => Ok(HttpStatus::MethodNotAllowed),
+            406 => Ok(HttpStatus::NotAcceptable),
+            407 => Ok(HttpStatus::ProxyAuthenticationRequired),
+            408 => Ok(HttpStatus::RequestTimeout),
+            409 => Ok(HttpStatus::Conflict),
+            410 => Ok(HttpStatus::Gone),
+            411 => Ok(HttpStatus::LengthRequired),
+            412 => Ok(HttpStatus::PreconditionFailed),
+            413 => Ok(HttpStatus::PayloadTooLarge),
+            414 => Ok(HttpStatus::UriTooLong),
+            415 => Ok(HttpStatus::UnsupportedMediaType),
+            416 => Ok(HttpStatus::RangeNotSatisfiable),
+            417 => Ok(HttpStatus::ExpectationFailed),
+            418 => Ok(HttpStatus::ImATeapot),
+            421 => Ok(HttpStatus::MisdirectedRequest),
+            422 => Ok(HttpStatus::UnprocessableEntity),
+            423 => Ok(HttpStatus::Locked),
+            424 => Ok(HttpStatus::FailedDependency),
+            425 => Ok(HttpStatus::TooEarly),
+            426 => Ok(HttpStatus::UpgradeRequired),
+            428 => Ok(HttpStatus::PreconditionRequired),
+            429 => Ok(HttpStatus::TooManyRequests),
+            431 => Ok(HttpStatus::RequestHeaderFieldsTooLarge),
+            451 => Ok(HttpStatus::UnavailableForLegalReasons),
             500 => Ok(HttpStatus::InternalError),
-            _ => Err("Unknown response type code")
+            501 => Ok(HttpStatus::NotImplemented),
+            502 => Ok(HttpStatus::BadGateway),
+            503 => Ok(HttpStatus::ServiceUnavailable),
+            504 => Ok(HttpStatus::GatewayTimeout),
+            505 => Ok(HttpStatus::HttpVersionNotSupported),
+            506 => Ok(HttpStatus::VariantAlsoNegotiates),
+            507 => Ok(HttpStatus::InsufficientStorage),
+            508 => Ok(HttpStatus::LoopDetected),
+            510 => Ok(HttpStatus::NotExtended),
+            511 => Ok(HttpStatus::NetworkAuthenticationRequired),
+            _ if (100..=599).contains(&code) => Ok(HttpStatus::Other(code, reason.to_string())),
+            _ => Err(HttpError::InvalidStatusCode(code))
         }
     }
 
     pub fn get_code(&self) -> i16 {
         match self {
+            HttpStatus::Continue => 100,
+            HttpStatus::SwitchingProtocols => 101,
+            HttpStatus::Processing => 102,
+            HttpStatus::EarlyHints => 103,
             HttpStatus::Ok => 200,
+            HttpStatus::Created => 201,
+            HttpStatus::Accepted => 202,
+            HttpStatus::NonAuthoritativeInformation => 203,
+            HttpStatus::NoContent => 204,
+            HttpStatus::ResetContent => 205,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::MultiStatus => 207,
+            HttpStatus::AlreadyReported => 208,
+            HttpStatus::ImUsed => 226,
+            HttpStatus::MultipleChoices => 300,
+            HttpStatus::MovedPermanently => 301,
+            HttpStatus::Found => 302,
+            HttpStatus::SeeOther => 303,
+            HttpStatus::NotModified => 304,
+            HttpStatus::UseProxy => 305,
+            HttpStatus::TemporaryRedirect => 307,
+            HttpStatus::PermanentRedirect => 308,
             HttpStatus::BadRequest => 400,
             HttpStatus::Unauthorized => 401,
+            HttpStatus::PaymentRequired => 402,
+            HttpStatus::Forbidden => 403,
             HttpStatus::NotFound => 404,
             HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::NotAcceptable => 406,
+            HttpStatus::ProxyAuthenticationRequired => 407,
+            HttpStatus::RequestTimeout => 408,
+            HttpStatus::Conflict => 409,
+            HttpStatus::Gone => 410,
+            HttpStatus::LengthRequired => 411,
+            HttpStatus::PreconditionFailed => 412,
+            HttpStatus::PayloadTooLarge => 413,
+            HttpStatus::UriTooLong => 414,
+            HttpStatus::UnsupportedMediaType => 415,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::ExpectationFailed => 417,
+            HttpStatus::ImATeapot => 418,
+            HttpStatus::MisdirectedRequest => 421,
+            HttpStatus::UnprocessableEntity => 422,
+            HttpStatus::Locked => 423,
+            HttpStatus::FailedDependency => 424,
+            HttpStatus::TooEarly => 425,
+            HttpStatus::UpgradeRequired => 426,
+            HttpStatus::PreconditionRequired => 428,
+            HttpStatus::TooManyRequests => 429,
+            HttpStatus::RequestHeaderFieldsTooLarge => 431,
+            HttpStatus::UnavailableForLegalReasons => 451,
             HttpStatus::InternalError => 500,
+            HttpStatus::NotImplemented => 501,
+            HttpStatus::BadGateway => 502,
+            HttpStatus::ServiceUnavailable => 503,
+            HttpStatus::GatewayTimeout => 504,
+            HttpStatus::HttpVersionNotSupported => 505,
+            HttpStatus::VariantAlsoNegotiates => 506,
+            HttpStatus::InsufficientStorage => 507,
+            HttpStatus::LoopDetected => 508,
+            HttpStatus::NotExtended => 510,
+            HttpStatus::NetworkAuthenticationRequired => 511,
+            HttpStatus::Other(code, _) => *code,
         }
     }
 
-    pub fn get_str(&self) -> &'static str {
+    pub fn get_str(&self) -> &str {
         match self {
+            HttpStatus::Continue => "Continue",
+            HttpStatus::SwitchingProtocols => "Switching Protocols",
+            HttpStatus::Processing => "Processing",
+            HttpStatus::EarlyHints => "Early Hints",
             HttpStatus::Ok => "OK",
+            HttpStatus::Created => "Created",
+            HttpStatus::Accepted => "Accepted",
+            HttpStatus::NonAuthoritativeInformation => "Non-Authoritative Information",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::ResetContent => "Reset Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::MultiStatus => "Multi-Status",
+            HttpStatus::AlreadyReported => "Already Reported",
+            HttpStatus::ImUsed => "IM Used",
+            HttpStatus::MultipleChoices => "Multiple Choices",
+            HttpStatus::MovedPermanently => "Moved Permanently",
+            HttpStatus::Found => "Found",
+            HttpStatus::SeeOther => "See Other",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::UseProxy => "Use Proxy",
+            HttpStatus::TemporaryRedirect => "Temporary Redirect",
+            HttpStatus::PermanentRedirect => "Permanent Redirect",
             HttpStatus::BadRequest => "Bad Request",
             HttpStatus::Unauthorized => "Unauthorized",
+            HttpStatus::PaymentRequired => "Payment Required",
+            HttpStatus::Forbidden => "Forbidden",
             HttpStatus::NotFound => "Not Found",
             HttpStatus::MethodNotAllowed => "Method Not Allowed",
-            HttpStatus::InternalError => "Internal Error"
+            HttpStatus::NotAcceptable => "Not Acceptable",
+            HttpStatus::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::Conflict => "Conflict",
+            HttpStatus::Gone => "Gone",
+            HttpStatus::LengthRequired => "Length Required",
+            HttpStatus::PreconditionFailed => "Precondition Failed",
+            HttpStatus::PayloadTooLarge => "Payload Too Large",
+            HttpStatus::UriTooLong => "URI Too Long",
+            HttpStatus::UnsupportedMediaType => "Unsupported Media Type",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::ExpectationFailed => "Expectation Failed",
+            HttpStatus::ImATeapot => "I'm a teapot",
+            HttpStatus::MisdirectedRequest => "Misdirected Request",
+            HttpStatus::UnprocessableEntity => "Unprocessable Entity",
+            HttpStatus::Locked => "Locked",
+            HttpStatus::FailedDependency => "Failed Dependency",
+            HttpStatus::TooEarly => "Too Early",
+            HttpStatus::UpgradeRequired => "Upgrade Required",
+            HttpStatus::PreconditionRequired => "Precondition Required",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatus::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
+            HttpStatus::InternalError => "Internal Server Error",
+            HttpStatus::NotImplemented => "Not Implemented",
+            HttpStatus::BadGateway => "Bad Gateway",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::GatewayTimeout => "Gateway Timeout",
+            HttpStatus::HttpVersionNotSupported => "HTTP Version Not Supported",
+            HttpStatus::VariantAlsoNegotiates => "Variant Also Negotiates",
+            HttpStatus::InsufficientStorage => "Insufficient Storage",
+            HttpStatus::LoopDetected => "Loop Detected",
+            HttpStatus::NotExtended => "Not Extended",
+            HttpStatus::NetworkAuthenticationRequired => "Network Authentication Required",
+            HttpStatus::Other(_, reason) => reason.as_str(),
         }
     }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.get_code())
+    }
 }
 
 impl HttpRequest {
@@ -129,45 +431,53 @@ impl HttpRequest {
         HttpRequest {
             header: HttpRequestHeader::create(route, verb, content_type, addition_headers, len),
             body,
+            remote_address: None,
+            principal: None,
+            received: Instant::now(),
         }
     }
 
-    pub fn from_stream(mut stream: &TcpStream, logger: &Logger) -> Result<HttpRequest, &'static str> {
-        let mut buffer = [0; 4096];
-        let mut body: Vec<u8> = Vec::new();
-        logger.log_debug(format!("Parsing http request header.")).unwrap();
-        stream.read(&mut buffer).unwrap();
-        logger.log_debug(format!("Read to buffer.")).unwrap();
-        let (header, body_start_index) = HttpRequestHeader::create_from_buffer(buffer)?;
-        let body = match (header.content_length > 0, body_start_index + header.content_length as usize > 4096) {
-            // Short cut -> content length is 0 so no body
-            (false, _) => {
-                None
-            }
-            // If the body_start_index + content length 
-            // the request of the body is bigger than the buffer and more reads needed
-            (true, true) => {
-                // TODO handle!
-                None
-            }
-            // If the body_start_index + content length < 2048,
-            // the body is in the initial buffer and no more reading is needed.
-            (true, false) => {
-                let end = body_start_index + header.content_length as usize;
+    pub fn builder(verb: HttpVerb, route: &str) -> HttpRequestBuilder {
+        HttpRequestBuilder {
+            verb,
+            route: route.to_string(),
+            content_type: "text/plain".to_string(),
+            headers: HashMap::new(),
+            query: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn from_stream<R: BufRead>(stream: &mut R, limits: &RequestLimits, logger: &Logger) -> Result<HttpRequest, HttpError> {
+        logger.log_debug("Parsing http request header.".to_string()).map_err(HttpError::Log)?;
+        let header = HttpRequestHeader::read_from_stream(stream, limits)?;
 
-                let body = buffer[body_start_index..end].to_vec();
+        HttpRequest::body_from_stream(header, stream, limits, logger)
+    }
 
-                Some(body)
+    /// Read the body of a request whose header has already been read.
+    pub fn body_from_stream<R: BufRead>(header: HttpRequestHeader, stream: &mut R, limits: &RequestLimits, logger: &Logger) -> Result<HttpRequest, HttpError> {
+        let body = match header.chunked {
+            true => {
+                logger.log_debug("Header read, reading chunked body.".to_string()).map_err(HttpError::Log)?;
+                read_chunked_body(stream, limits.max_body_size)?
+            }
+            false => {
+                logger.log_debug(format!("Header read, reading body ({} bytes).", header.content_length)).map_err(HttpError::Log)?;
+                read_body(stream, header.content_length, limits.max_body_size)?
             }
         };
 
         Ok(HttpRequest {
             header,
             body,
+            remote_address: None,
+            principal: None,
+            received: Instant::now(),
         })
     }
 
-    pub fn to_bytes(&mut self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
 
         // Get the bytes for the header and append the response body.
         let mut bytes = self.header.to_bytes();
@@ -182,6 +492,47 @@ impl HttpRequest {
     }
 }
 
+impl HttpRequestBuilder {
+    pub fn header(mut self, key: &str, value: &str) -> HttpRequestBuilder {
+        self.headers.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Add a query parameter, the key and value are percent-encoded when the request is built.
+    pub fn query(mut self, key: &str, value: &str) -> HttpRequestBuilder {
+        self.query.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: Vec<u8>) -> HttpRequestBuilder {
+        self.content_type = content_type.to_string();
+        self.body = Some(body);
+        self
+    }
+
+    pub fn json<T: Serialize>(self, value: &T) -> Result<HttpRequestBuilder, HttpError> {
+        match serde_json::to_vec(value) {
+            Ok(body) => Ok(self.body("application/json", body)),
+            Err(_) => Err(HttpError::Serialization("Could not serialize request body."))
+        }
+    }
+
+    pub fn build(self) -> HttpRequest {
+        let mut route = self.route;
+
+        if !self.query.is_empty() {
+            let query: Vec<String> = self.query.iter()
+                .map(|(k, v)| format!("{}={}", encode_component(k), encode_component(v)))
+                .collect();
+
+            route.push(if route.contains('?') { '&' } else { '?' });
+            route.push_str(&query.join("&"));
+        }
+
+        HttpRequest::create(route, self.verb, self.content_type, self.headers, self.body)
+    }
+}
+
 impl HttpRequestHeader {
     pub fn create(route: String, verb: HttpVerb, content_type: String, addition_headers: HashMap<String, String>, content_length: usize) -> HttpRequestHeader {
         let http_version = String::from("HTTP/1.1");
@@ -192,7 +543,7 @@ impl HttpRequestHeader {
         // Add any standardized headers.
         headers.insert("Server".to_string(), "Psionic 0.0.1".to_string());
         headers.insert("Content-Length".to_string(), format!("{}", content_length));
-        headers.insert("Connection".to_string(), "Closed".to_string());
+        headers.insert("Connection".to_string(), "close".to_string());
         headers.insert("Content-Type".to_string(), content_type);
 
         for (k, v) in addition_headers {
@@ -200,52 +551,49 @@ impl HttpRequestHeader {
         }
 
         HttpRequestHeader {
+            url: Url::parse(&route),
             route,
             verb,
             content_length,
+            chunked: false,
             headers,
             http_version,
         }
     }
 
-    pub fn create_from_buffer(buffer: [u8; 4096]) -> Result<(HttpRequestHeader, usize), &'static str> {
-        for i in 0..buffer.len() {
-            if i > 4 &&
-                buffer[i] == 10 &&
-                buffer[i - 1] == 13 &&
-                buffer[i - 2] == 10 &&
-                buffer[i - 3] == 13 {
-                // \r\n\r\n found, after this its the body.
-                let header = String::from_utf8_lossy(&buffer[0..i]).into_owned();
-
-                //println!("{}", header);
-
-                let request = HttpRequestHeader::parse_from_string(header)?;
+    pub fn read_from_stream<R: BufRead>(stream: &mut R, limits: &RequestLimits) -> Result<HttpRequestHeader, HttpError> {
+        let header = read_header_block(stream, limits.max_header_size)?;
 
-                return Ok((request, i + 1));
-            }
+        // Every line after the request line is a header.
+        if header.split("\r\n").count() - 1 > limits.max_header_count {
+            return Err(HttpError::TooManyHeaders(limits.max_header_count));
         }
 
-        Err("Request header larger than buffer")
+        HttpRequestHeader::parse_from_string(header)
     }
 
-    pub fn parse_from_string(data: String) -> Result<HttpRequestHeader, &'static str> {
+    pub fn parse_from_string(data: String) -> Result<HttpRequestHeader, HttpError> {
         let split_header: Vec<&str> = data.split("\r\n").collect();
 
         let mut headers = HashMap::new();
 
         let mut content_length: usize = 0;
+        let mut chunked = false;
+
+        let split_status_line: Vec<&str> = split_header[0].split(' ').collect();
 
-        let split_status_line: Vec<&str> = split_header[0].split(" ").collect();
+        if split_status_line.len() != 3 {
+            return Err(HttpError::MalformedRequestLine(split_header[0].to_string()));
+        }
 
-        let verb = HttpVerb::from_str(split_status_line[0])?;
+        let verb = split_status_line[0].parse::<HttpVerb>()?;
         let route = String::from(split_status_line[1]);
         let http_version = String::from(split_status_line[2]);
 
-        for i in 1..split_header.len() {
-            //println!("Head: {}", split_header[i]);
+        for line in split_header.iter().skip(1) {
+            //println!("Head: {}", line);
 
-            let split_item: Vec<&str> = split_header[i].split(": ").collect();
+            let split_item: Vec<&str> = line.split(": ").collect();
 
             // If the split item has more than 1 item, add a header.
             if split_item.len() > 1 {
@@ -254,20 +602,26 @@ impl HttpRequestHeader {
 
                 // If the header item is `Content-Length` set it as such.
                 if k == "CONTENT-LENGTH" {
-                    match v.parse::<usize>() {
-                        Ok(i) => content_length = i,
-                        Err(_) => {}
+                    if let Ok(i) = v.parse::<usize>() {
+                        content_length = i;
                     }
                 }
 
+                // If the body is sent in chunks the length is not known up front.
+                if k == "TRANSFER-ENCODING" {
+                    chunked = is_chunked_encoding(&v);
+                }
+
                 headers.insert(k, v);
             }
         }
 
         Ok(HttpRequestHeader {
+            url: Url::parse(&route),
             route,
             verb,
             content_length,
+            chunked,
             headers,
             http_version,
         })
@@ -276,7 +630,7 @@ impl HttpRequestHeader {
     pub fn get_string(&self) -> String {
         let mut header_string = String::new();
 
-        header_string.push_str(&self.verb.get_str());
+        header_string.push_str(self.verb.get_str());
         header_string.push(' ');
         header_string.push_str(&self.route);
         header_string.push(' ');
@@ -285,9 +639,9 @@ impl HttpRequestHeader {
         header_string.push_str("\r\n");
 
         for header in &self.headers {
-            header_string.push_str(&header.0);
+            header_string.push_str(header.0);
             header_string.push_str(": ");
-            header_string.push_str(&header.1);
+            header_string.push_str(header.1);
             header_string.push_str("\r\n");
         }
 
@@ -295,10 +649,8 @@ impl HttpRequestHeader {
         header_string
     }
 
-    pub fn to_bytes(&mut self) -> Vec<u8> {
-        let mut bytes = Vec::from(self.get_string().as_bytes());
-
-        bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Vec::from(self.get_string())
     }
 }
 
@@ -312,52 +664,52 @@ impl HttpResponse {
         HttpResponse {
             header: HttpResponseHeader::create(status, content_type, addition_headers, len),
             body,
+            stream: None,
         }
     }
 
-    pub fn from_stream(mut stream: &TcpStream/*, logger: &Logger*/) -> Result<HttpResponse, &'static str> {
-        let mut buffer = [0; 4096];
-        let mut body: Vec<u8> = Vec::new();
-        //logger.log_debug( format!("Parsing http response header.")).unwrap();
-        let read = stream.read(&mut buffer).unwrap();
-        //logger.log_debug(format!("Read to buffer.")).unwrap();
-        let (header, body_start_index) = HttpResponseHeader::create_from_buffer(buffer)?;
-        let body = match (header.content_length > 0, body_start_index + header.content_length as usize > 4096) {
-            // Short cut -> content length is 0 so no body
-            (false, _) => {
-                None
-            }
-            // If the body_start_index + content length 
-            // the request of the body is bigger than the buffer and more reads needed
-            (true, true) => {
-                // TODO handle!
-                None
-            }
-            // If the body_start_index + content length < 2048,
-            // the body is in the initial buffer and no more reading is needed.
-            (true, false) => {
-                if read == body_start_index {
-                    // Only head was send (might be general.
-                    // Therefore clear the array
-                    buffer.fill(0);
-                    stream.read(&mut buffer).unwrap();
-                    body = buffer[0..header.content_length].to_vec();
-                } else {
-                    let end = body_start_index + header.content_length as usize;
-                    body = buffer[body_start_index..end].to_vec();
-                }
+    /// Create a response whose body is produced as it is written, for output of unknown length.
+    /// Each item from `stream` is sent as a single chunk.
+    pub fn create_chunked(status: HttpStatus, content_type: String, addition_headers: HashMap<String, String>, stream: BodyStream) -> HttpResponse {
+        let mut header = HttpResponseHeader::create(status, content_type, addition_headers, 0);
 
-                Some(body)
-            }
+        header.headers.remove("Content-Length");
+        header.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        header.chunked = true;
+
+        HttpResponse {
+            header,
+            body: None,
+            stream: Some(stream),
+        }
+    }
+
+    pub fn from_stream<R: BufRead>(stream: &mut R, max_body_size: usize) -> Result<HttpResponse, HttpError> {
+        let header = HttpResponseHeader::read_from_stream(stream)?;
+        let body = match header.chunked {
+            true => read_chunked_body(stream, max_body_size)?,
+            false => read_body(stream, header.content_length, max_body_size)?
         };
 
         Ok(HttpResponse {
             header,
             body,
+            stream: None,
+        })
+    }
+
+    /// Read a response that has no body regardless of its headers, such as the reply to a `HEAD` request.
+    pub fn header_only_from_stream<R: BufRead>(stream: &mut R) -> Result<HttpResponse, HttpError> {
+        let header = HttpResponseHeader::read_from_stream(stream)?;
+
+        Ok(HttpResponse {
+            header,
+            body: None,
+            stream: None,
         })
     }
 
-    pub fn to_bytes(&mut self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
 
         // Get the bytes for the header and append the response body.
         let mut bytes = self.header.to_bytes();
@@ -370,6 +722,31 @@ impl HttpResponse {
 
         bytes
     }
+
+    /// Write the response to a stream. A chunked response is written one chunk at a time,
+    /// flushing after each so the other side sees output as it is produced.
+    pub fn write_to<W: Write>(&mut self, stream: &mut W) -> std::io::Result<()> {
+        match self.stream.take() {
+            None => {
+                stream.write_all(&self.to_bytes())?;
+            }
+            Some(chunks) => {
+                stream.write_all(&self.header.to_bytes())?;
+                stream.flush()?;
+
+                for chunk in chunks {
+                    // A zero length chunk would end the body early.
+                    if !chunk.is_empty() {
+                        write_chunk(stream, &chunk)?;
+                    }
+                }
+
+                write_chunk(stream, &[])?;
+            }
+        }
+
+        stream.flush()
+    }
 }
 
 impl HttpResponseHeader {
@@ -382,7 +759,7 @@ impl HttpResponseHeader {
         // Add any standardized headers.
         headers.insert("Server".to_string(), "Psionic 0.0.1".to_string());
         headers.insert("Content-Length".to_string(), format!("{}", content_length));
-        headers.insert("Connection".to_string(), "Closed".to_string());
+        headers.insert("Connection".to_string(), "close".to_string());
         headers.insert("Content-Type".to_string(), content_type);
 
         for (k, v) in addition_headers {
@@ -393,58 +770,51 @@ impl HttpResponseHeader {
             http_version,
             status,
             content_length,
+            chunked: false,
             headers,
         }
     }
 
-    pub fn create_from_buffer(buffer: [u8; 4096]) -> Result<(HttpResponseHeader, usize), &'static str> {
-        for i in 0..buffer.len() {
-            if i > 4 &&
-                buffer[i] == 10 &&
-                buffer[i - 1] == 13 &&
-                buffer[i - 2] == 10 &&
-                buffer[i - 3] == 13 {
-                // \r\n\r\n found, after this its the body.
-                let header = String::from_utf8_lossy(&buffer[0..i]).into_owned();
+    pub fn read_from_stream<R: BufRead>(stream: &mut R) -> Result<HttpResponseHeader, HttpError> {
+        let header = read_header_block(stream, MAX_HEADER_SIZE)?;
 
-                //println!("{}", header);
-
-                let response = HttpResponseHeader::parse_from_string(header)?;
-
-                return Ok((response, i + 1));
-            }
-        }
-
-        Err("Request header larger than buffer")
+        HttpResponseHeader::parse_from_string(header)
     }
 
-    pub fn parse_from_string(data: String) -> Result<HttpResponseHeader, &'static str> {
+    pub fn parse_from_string(data: String) -> Result<HttpResponseHeader, HttpError> {
         let split_header: Vec<&str> = data.split("\r\n").collect();
 
         let mut headers = HashMap::new();
 
         let mut content_length: usize = 0;
+        let mut chunked = false;
+
+        // The reason phrase may contain spaces, so only split off the version and code.
+        let split_status_line: Vec<&str> = split_header[0].splitn(3, ' ').collect();
 
-        let split_status_line: Vec<&str> = split_header[0].split(" ").collect();
+        if split_status_line.len() < 2 {
+            return Err(HttpError::MalformedStatusLine(split_header[0].to_string()));
+        }
 
         //let verb = HttpVerb::from_str(split_status_line[0])?;
         //let route = String::from(split_status_line[1]);
         let http_version = String::from(split_status_line[0]);
         //let response = split_status_line[1].parse::<i32>();
+        let reason = split_status_line.get(2).unwrap_or(&"");
 
         let status = match split_status_line[1].parse::<i16>() {
             Ok(status_code) => {
-                HttpStatus::from_code(status_code)
+                HttpStatus::from_code_and_reason(status_code, reason)
             }
             Err(_) => {
-                Err("Failed to parse status code")
+                Err(HttpError::MalformedStatusLine(split_header[0].to_string()))
             }
         }?;
 
-        for i in 1..split_header.len() {
-            //println!("Head: {}", split_header[i]);
+        for line in split_header.iter().skip(1) {
+            //println!("Head: {}", line);
 
-            let split_item: Vec<&str> = split_header[i].split(": ").collect();
+            let split_item: Vec<&str> = line.split(": ").collect();
 
             // If the split item has more than 1 item, add a header.
             if split_item.len() > 1 {
@@ -453,12 +823,16 @@ impl HttpResponseHeader {
 
                 // If the header item is `Content-Length` set it as such.
                 if k == "CONTENT-LENGTH" {
-                    match v.parse::<usize>() {
-                        Ok(i) => content_length = i,
-                        Err(_) => {}
+                    if let Ok(i) = v.parse::<usize>() {
+                        content_length = i;
                     }
                 }
 
+                // If the body is sent in chunks the length is not known up front.
+                if k == "TRANSFER-ENCODING" {
+                    chunked = is_chunked_encoding(&v);
+                }
+
                 headers.insert(k, v);
             }
         }
@@ -468,6 +842,7 @@ impl HttpResponseHeader {
             http_version,
             status,
             content_length,
+            chunked,
         })
     }
 
@@ -485,9 +860,9 @@ impl HttpResponseHeader {
         header_string.push_str("\r\n");
 
         for header in &self.headers {
-            header_string.push_str(&header.0);
+            header_string.push_str(header.0);
             header_string.push_str(": ");
-            header_string.push_str(&header.1);
+            header_string.push_str(header.1);
             header_string.push_str("\r\n");
         }
 
@@ -495,11 +870,320 @@ impl HttpResponseHeader {
         header_string
     }
 
-    pub fn to_bytes(&mut self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Vec::from(self.get_string())
+    }
+}
 
-        // Get the bytes for the header and append the response body.
-        let mut bytes = Vec::from(self.get_string());
+/// Read from the stream until the blank line that ends the header block.
+/// The returned string does not include the final `\r\n\r\n`.
+fn read_header_block<R: BufRead>(stream: &mut R, max_size: usize) -> Result<String, HttpError> {
+    let mut buffer: Vec<u8> = Vec::new();
 
-        bytes
+    while !buffer.ends_with(b"\r\n\r\n") {
+        if buffer.len() >= max_size {
+            return Err(HttpError::HeaderTooLarge);
+        }
+
+        let remaining = (max_size - buffer.len()) as u64;
+
+        if stream.by_ref().take(remaining).read_until(b'\n', &mut buffer)? == 0 {
+            return Err(HttpError::ConnectionClosed);
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&buffer[0..buffer.len() - 4]).into_owned())
+}
+
+/// Keep reading from the stream until `content_length` bytes of body have arrived.
+fn read_body<R: BufRead>(stream: &mut R, content_length: usize, max_body_size: usize) -> Result<Option<Vec<u8>>, HttpError> {
+    // Short cut -> content length is 0 so no body
+    if content_length == 0 {
+        return Ok(None);
+    }
+
+    if content_length > max_body_size {
+        return Err(HttpError::BodyTooLarge(max_body_size));
+    }
+
+    let mut body: Vec<u8> = Vec::with_capacity(content_length);
+
+    match stream.by_ref().take(content_length as u64).read_to_end(&mut body)? == content_length {
+        true => Ok(Some(body)),
+        false => Err(HttpError::ConnectionClosed)
     }
-}
\ No newline at end of file
+}
+
+/// Read a body sent with chunked transfer-encoding and join the chunks together.
+/// Chunk extensions and trailers are read but discarded.
+fn read_chunked_body<R: BufRead>(stream: &mut R, max_body_size: usize) -> Result<Option<Vec<u8>>, HttpError> {
+    let mut body: Vec<u8> = Vec::new();
+
+    loop {
+        let size = parse_chunk_size(&read_chunk_line(stream)?)?;
+
+        if size == 0 {
+            break;
+        }
+
+        // Compared this way round so a huge size cannot overflow.
+        if size > max_body_size - body.len() {
+            return Err(HttpError::BodyTooLarge(max_body_size));
+        }
+
+        if stream.by_ref().take(size as u64).read_to_end(&mut body)? != size {
+            return Err(HttpError::ConnectionClosed);
+        }
+
+        // Each chunk is followed by a CRLF.
+        if !read_chunk_line(stream)?.is_empty() {
+            return Err(HttpError::MalformedChunk);
+        }
+    }
+
+    // Skip any trailers, the body ends with an empty line.
+    while !read_chunk_line(stream)?.is_empty() {}
+
+    match body.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(body))
+    }
+}
+
+/// The size from a chunk size line, ignoring any extensions.
+fn parse_chunk_size(line: &str) -> Result<usize, HttpError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+
+    // `from_str_radix` would also take a leading `+`, only hex digits are allowed.
+    if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpError::MalformedChunk);
+    }
+
+    usize::from_str_radix(size, 16).map_err(|_| HttpError::MalformedChunk)
+}
+
+/// Read a single CRLF terminated line of the chunked framing, without the CRLF.
+fn read_chunk_line<R: BufRead>(stream: &mut R) -> Result<String, HttpError> {
+    let mut line: Vec<u8> = Vec::new();
+
+    match stream.by_ref().take(MAX_HEADER_SIZE as u64).read_until(b'\n', &mut line)? {
+        _ if line.ends_with(b"\r\n") => {
+            Ok(String::from_utf8_lossy(&line[0..line.len() - 2]).into_owned())
+        }
+        0 => Err(HttpError::ConnectionClosed),
+        _ => Err(HttpError::MalformedChunk)
+    }
+}
+
+/// Finds where a chunked body ends as it arrives, so it is only read once all of it is there.
+/// Each call carries on from the chunks already stepped over.
+#[derive(Default)]
+pub struct ChunkedScan {
+    /// How far into the body the complete chunks go.
+    position: usize,
+    size: usize,
+    /// The last chunk has been seen, only trailers are left.
+    trailers: bool,
+}
+
+impl ChunkedScan {
+    /// Step over the chunks that have fully arrived in `body`. Returns true once the whole body is there,
+    /// or once the framing is wrong or too large, which reading the body will then report.
+    pub fn advance(&mut self, body: &[u8], max_body_size: usize) -> bool {
+        loop {
+            let rest = &body[self.position..];
+
+            let line_end = match rest.iter().position(|b| *b == b'\n') {
+                Some(end) => end,
+                None => return rest.len() >= MAX_HEADER_SIZE
+            };
+
+            if line_end == 0 || rest[line_end - 1] != b'\r' {
+                return true;
+            }
+
+            let line = &rest[..line_end - 1];
+            let next = self.position + line_end + 1;
+
+            if self.trailers {
+                self.position = next;
+
+                match line.is_empty() {
+                    true => return true,
+                    false => continue
+                }
+            }
+
+            let size = match parse_chunk_size(&String::from_utf8_lossy(line)) {
+                Ok(0) => {
+                    self.trailers = true;
+                    self.position = next;
+                    continue;
+                }
+                Ok(size) if size <= max_body_size - self.size => size,
+                _ => return true
+            };
+
+            // The chunk and the CRLF after it.
+            if body.len() - next < size + 2 {
+                return false;
+            }
+
+            self.size += size;
+            self.position = next + size + 2;
+        }
+    }
+}
+
+/// Write a single chunk. An empty chunk marks the end of the body.
+pub fn write_chunk<W: Write>(stream: &mut W, chunk: &[u8]) -> std::io::Result<()> {
+    stream.write_all(format!("{:X}\r\n", chunk.len()).as_bytes())?;
+    stream.write_all(chunk)?;
+    stream.write_all(b"\r\n")?;
+
+    match chunk.is_empty() {
+        true => Ok(()),
+        false => stream.flush()
+    }
+}
+
+/// Headers set by the server keep the case they were added with, those read from a peer are upper case.
+pub(crate) fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<(&'a String, &'a String)> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name))
+}
+
+pub(crate) fn set_header(headers: &mut HashMap<String, String>, name: &str, value: String) {
+    let key = find_header(headers, name).map(|(k, _)| k.clone()).unwrap_or_else(|| name.to_string());
+
+    headers.insert(key, value);
+}
+
+/// Add a request header the response depends on to its `Vary`.
+pub(crate) fn add_vary(headers: &mut HashMap<String, String>, field: &str) {
+    match find_header(headers, "Vary").map(|(k, v)| (k.clone(), v.clone())) {
+        Some((_, vary)) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(field) || v.trim() == "*") => {}
+        Some((key, vary)) => {
+            headers.insert(key, format!("{}, {}", vary, field));
+        }
+        None => {
+            headers.insert("Vary".to_string(), field.to_string());
+        }
+    }
+}
+
+/// Chunked must be the final transfer-coding applied for the body to be chunk framed.
+fn is_chunked_encoding(value: &str) -> bool {
+    match value.split(',').next_back() {
+        None => false,
+        Some(coding) => coding.trim().eq_ignore_ascii_case("chunked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    fn read_chunked(input: &str, max_body_size: usize) -> Result<Option<Vec<u8>>, HttpError> {
+        read_chunked_body(&mut Cursor::new(input.as_bytes().to_vec()), max_body_size)
+    }
+
+    #[test]
+    fn reads_chunks_into_one_body() {
+        let body = read_chunked("4\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n", 1024).unwrap();
+
+        assert_eq!(body.unwrap(), b"Wikipedia in \r\n\r\nchunks.");
+    }
+
+    #[test]
+    fn skips_extensions_and_trailers() {
+        let body = read_chunked("5;name=value\r\nhello\r\n0\r\nExpires: never\r\n\r\n", 1024).unwrap();
+
+        assert_eq!(body.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn empty_chunked_body_is_none() {
+        assert!(read_chunked("0\r\n\r\n", 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn chunked_scan_finds_the_end_as_it_arrives() {
+        let body = b"4\r\nWiki\r\n6;x=y\r\npedia \r\n0\r\nExpires: never\r\n\r\n";
+        let mut scan = ChunkedScan::default();
+
+        for length in 0..body.len() {
+            assert!(!scan.advance(&body[..length], 1024), "length {}", length);
+        }
+
+        assert!(scan.advance(body, 1024));
+    }
+
+    #[test]
+    fn chunked_scan_leaves_bad_framing_to_the_reader() {
+        for body in ["x\r\n", "4\nWiki", "4\r\nWiki\r\n8\r\n"] {
+            assert!(ChunkedScan::default().advance(body.as_bytes(), 10), "body {:?}", body);
+        }
+
+        assert!(ChunkedScan::default().advance(&[b'4'; MAX_HEADER_SIZE], 1024));
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        for size in ["", "g", "-1", "+5", "5 5", "0x5"] {
+            let input = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+
+            assert!(matches!(read_chunked(&input, 1024), Err(HttpError::MalformedChunk)), "size {:?}", size);
+        }
+    }
+
+    #[test]
+    fn rejects_a_size_too_large_to_add_up() {
+        assert!(matches!(read_chunked("FFFFFFFFFFFFFFFF\r\nhello\r\n0\r\n\r\n", 1024), Err(HttpError::BodyTooLarge(1024))));
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_limit() {
+        assert!(matches!(read_chunked("4\r\nWiki\r\n4\r\npedi\r\n0\r\n\r\n", 6), Err(HttpError::BodyTooLarge(6))));
+    }
+
+    #[test]
+    fn rejects_chunks_longer_than_their_size() {
+        assert!(matches!(read_chunked("3\r\nhello\r\n0\r\n\r\n", 1024), Err(HttpError::MalformedChunk)));
+    }
+
+    #[test]
+    fn rejects_lines_without_crlf() {
+        assert!(matches!(read_chunked("5\nhello\r\n0\r\n\r\n", 1024), Err(HttpError::MalformedChunk)));
+    }
+
+    #[test]
+    fn truncated_body_is_a_closed_connection() {
+        assert!(matches!(read_chunked("5\r\nhel", 1024), Err(HttpError::ConnectionClosed)));
+        assert!(matches!(read_chunked("5\r\nhello\r\n", 1024), Err(HttpError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn writes_chunks_that_read_back() {
+        let mut written: Vec<u8> = Vec::new();
+
+        write_chunk(&mut written, b"hello ").unwrap();
+        write_chunk(&mut written, &[b'x'; 26]).unwrap();
+        write_chunk(&mut written, b"").unwrap();
+
+        assert!(written.starts_with(b"6\r\nhello \r\n1A\r\n"));
+        assert!(written.ends_with(b"xx\r\n0\r\n\r\n"));
+
+        let body = read_chunked_body(&mut Cursor::new(written), 1024).unwrap().unwrap();
+        assert_eq!(body.len(), 32);
+        assert!(body.starts_with(b"hello x"));
+    }
+
+    #[test]
+    fn chunked_only_if_it_is_the_final_coding() {
+        assert!(is_chunked_encoding("chunked"));
+        assert!(is_chunked_encoding("gzip, Chunked"));
+        assert!(!is_chunked_encoding("chunked, gzip"));
+        assert!(!is_chunked_encoding(""));
+    }
+}
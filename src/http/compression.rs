@@ -0,0 +1,375 @@
+use std::io::{Read, Write};
+use std::str::FromStr;
+use flate2::Compression;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use crate::http::common::{add_vary, find_header, HttpResponse, HttpStatus, set_header};
+use crate::http::error::HttpError;
+use crate::http::middleware::{Middleware, RequestInfo};
+use crate::http::router::RouteResult;
+
+/// The `Accept-Encoding` sent by the client.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentCoding {
+    Gzip,
+    /// The zlib format, as `deflate` means in http.
+    Deflate,
+}
+
+/// Which responses are compressed.
+#[derive(Clone)]
+pub struct CompressionSettings {
+    /// Smaller bodies are sent as they are, compressing them gains little.
+    pub min_size: usize,
+    /// Media types that are compressed. An entry ending in `/` covers every subtype, such as `text/`.
+    pub content_types: Vec<String>,
+}
+
+/// Compresses response bodies in an encoding the client accepts.
+pub(crate) struct CompressionMiddleware {
+    settings: CompressionSettings,
+}
+
+impl FromStr for ContentCoding {
+    type Err = HttpError;
+
+    fn from_str(value: &str) -> Result<ContentCoding, HttpError> {
+        match value.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Ok(ContentCoding::Gzip),
+            "deflate" => Ok(ContentCoding::Deflate),
+            _ => Err(HttpError::InvalidResponse("Unsupported content encoding."))
+        }
+    }
+}
+
+impl ContentCoding {
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentCoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentCoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decode a body, refusing to expand it past `max_size`.
+    pub fn decode(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, HttpError> {
+        match self {
+            ContentCoding::Gzip => read_limited(GzDecoder::new(data), max_size),
+            // Some servers send raw deflate instead of zlib.
+            ContentCoding::Deflate => match read_limited(ZlibDecoder::new(data), max_size) {
+                Err(HttpError::Io(_)) => read_limited(DeflateDecoder::new(data), max_size),
+                result => result
+            }
+        }
+    }
+}
+
+/// Pick the coding the client prefers from an `Accept-Encoding` value, gzip if it likes both equally.
+/// `None` if it accepts neither.
+pub fn negotiate(accept_encoding: &str) -> Option<ContentCoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_lowercase();
+
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "deflate" => deflate = Some(quality),
+            "*" => any = Some(quality),
+            _ => {}
+        }
+    }
+
+    // `*` covers the codings not named.
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+
+    match (gzip, deflate) {
+        (g, d) if g > 0.0 && g >= d => Some(ContentCoding::Gzip),
+        (_, d) if d > 0.0 => Some(ContentCoding::Deflate),
+        _ => None
+    }
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        CompressionSettings {
+            min_size: 1024,
+            content_types: vec![
+                "text/".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "image/svg+xml".to_string(),
+            ],
+        }
+    }
+}
+
+impl CompressionSettings {
+    fn allows(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+        self.content_types.iter().any(|t| match t.ends_with('/') {
+            true => media_type.starts_with(t.as_str()),
+            false => media_type == *t
+        })
+    }
+}
+
+impl CompressionMiddleware {
+    pub fn create(settings: CompressionSettings) -> CompressionMiddleware {
+        CompressionMiddleware { settings }
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn after(&self, request: &RequestInfo, result: &mut RouteResult) {
+        if let Some(accept_encoding) = request.headers.get("ACCEPT-ENCODING") {
+            compress_response(&mut result.response, accept_encoding, &self.settings);
+        }
+    }
+}
+
+/// Compress the body of a response if the client accepts it and the settings allow it.
+pub(crate) fn compress_response(response: &mut HttpResponse, accept_encoding: &str, settings: &CompressionSettings) {
+    // Streamed bodies are sent as they are produced, compressing them would hold them back.
+    let body = match &response.body {
+        Some(body) if response.stream.is_none() && body.len() >= settings.min_size => body,
+        _ => return
+    };
+
+    if matches!(response.header.status, HttpStatus::NoContent | HttpStatus::NotModified | HttpStatus::SwitchingProtocols) {
+        return;
+    }
+
+    let headers = &mut response.header.headers;
+
+    if find_header(headers, "Content-Encoding").is_some() {
+        return;
+    }
+
+    match find_header(headers, "Content-Type") {
+        Some((_, content_type)) if settings.allows(content_type) => {}
+        _ => return
+    }
+
+    // Responses differ by `Accept-Encoding` from here on, whether compressed or not.
+    add_vary(headers, "Accept-Encoding");
+
+    let coding = match negotiate(accept_encoding) {
+        Some(coding) => coding,
+        None => return
+    };
+
+    let compressed = match coding.encode(body) {
+        Ok(compressed) if compressed.len() < body.len() => compressed,
+        _ => return
+    };
+
+    headers.insert("Content-Encoding".to_string(), coding.get_str().to_string());
+    set_header(headers, "Content-Length", compressed.len().to_string());
+
+    // The compressed bytes differ, so a strong validator would no longer hold.
+    if let Some((key, etag)) = find_header(headers, "ETag") {
+        if !etag.starts_with("W/") {
+            let (key, etag) = (key.clone(), format!("W/{}", etag));
+            headers.insert(key, etag);
+        }
+    }
+
+    response.header.content_length = compressed.len();
+    response.body = Some(compressed);
+}
+
+/// Undo the `Content-Encoding` of a response read from a server, leaving it as if it had been sent uncompressed.
+pub(crate) fn decompress_response(response: &mut HttpResponse, max_size: usize) -> Result<(), HttpError> {
+    let coding = match find_header(&response.header.headers, "Content-Encoding") {
+        Some((_, value)) => match value.trim() {
+            "" | "identity" => None,
+            value => Some(value.parse::<ContentCoding>()?)
+        },
+        None => return Ok(())
+    };
+
+    let headers = &mut response.header.headers;
+    headers.retain(|k, _| !k.eq_ignore_ascii_case("Content-Encoding"));
+
+    if let (Some(coding), Some(body)) = (coding, &response.body) {
+        let body = coding.decode(body, max_size)?;
+
+        set_header(headers, "Content-Length", body.len().to_string());
+        response.header.content_length = body.len();
+        response.body = Some(body);
+    }
+
+    Ok(())
+}
+
+fn read_limited<R: Read>(decoder: R, max_size: usize) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+
+    decoder.take(max_size as u64 + 1).read_to_end(&mut body).map_err(HttpError::Io)?;
+
+    match body.len() > max_size {
+        true => Err(HttpError::BodyTooLarge(max_size)),
+        false => Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use flate2::write::DeflateEncoder;
+    use super::*;
+
+    fn response(content_type: &str, body: Vec<u8>) -> HttpResponse {
+        let mut headers = HashMap::new();
+        headers.insert("ETag".to_string(), "\"abc\"".to_string());
+
+        HttpResponse::create(HttpStatus::Ok, content_type.to_string(), headers, Some(body))
+    }
+
+    fn text(length: usize) -> Vec<u8> {
+        "all work and no play ".bytes().cycle().take(length).collect()
+    }
+
+    fn encoded(response: &HttpResponse) -> Option<&str> {
+        find_header(&response.header.headers, "Content-Encoding").map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn negotiate_prefers_the_higher_quality() {
+        assert_eq!(negotiate("gzip, deflate"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("deflate"), Some(ContentCoding::Deflate));
+        assert_eq!(negotiate("deflate, gzip;q=0.8"), Some(ContentCoding::Deflate));
+        assert_eq!(negotiate("GZIP;q=0.2, deflate;q=0.1"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("x-gzip"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("br, identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn negotiate_breaks_ties_with_gzip() {
+        assert_eq!(negotiate("deflate;q=0.5, gzip;q=0.5"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(ContentCoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_refuses_codings_with_zero_quality() {
+        assert_eq!(negotiate("gzip;q=0, deflate"), Some(ContentCoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("gzip;q=0.0, deflate;q=0"), None);
+    }
+
+    #[test]
+    fn negotiate_star_covers_codings_not_named() {
+        assert_eq!(negotiate("*"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("*;q=0.5, gzip;q=0"), Some(ContentCoding::Deflate));
+        assert_eq!(negotiate("deflate;q=0.9, *;q=0.1"), Some(ContentCoding::Deflate));
+        assert_eq!(negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn compresses_allowed_types_and_weakens_the_etag() {
+        let body = text(4096);
+        let mut response = response("text/html; charset=utf-8", body.clone());
+
+        compress_response(&mut response, "gzip", &CompressionSettings::default());
+
+        assert_eq!(encoded(&response), Some("gzip"));
+        assert_eq!(response.header.headers["Vary"], "Accept-Encoding");
+        assert_eq!(response.header.headers["ETag"], "W/\"abc\"");
+        assert_eq!(response.header.content_length, response.body.as_ref().unwrap().len());
+        assert_eq!(ContentCoding::Gzip.decode(response.body.as_ref().unwrap(), body.len()).unwrap(), body);
+    }
+
+    #[test]
+    fn bodies_under_the_minimum_size_are_left_alone() {
+        let settings = CompressionSettings { min_size: 100, ..CompressionSettings::default() };
+
+        let mut small = response("application/json", text(99));
+        compress_response(&mut small, "gzip", &settings);
+        assert_eq!(encoded(&small), None);
+        assert!(!small.header.headers.contains_key("Vary"));
+
+        let mut large = response("application/json", text(100));
+        compress_response(&mut large, "gzip", &settings);
+        assert_eq!(encoded(&large), Some("gzip"));
+    }
+
+    #[test]
+    fn only_allowed_content_types_are_compressed() {
+        let settings = CompressionSettings::default();
+
+        for content_type in ["text/css", "TEXT/PLAIN; charset=utf-8", "application/json", "image/svg+xml"] {
+            let mut response = response(content_type, text(4096));
+            compress_response(&mut response, "gzip", &settings);
+            assert_eq!(encoded(&response), Some("gzip"), "{}", content_type);
+        }
+
+        for content_type in ["image/png", "application/json-seq", "application/octet-stream", "textual/plain"] {
+            let mut response = response(content_type, text(4096));
+            compress_response(&mut response, "gzip", &settings);
+            assert_eq!(encoded(&response), None, "{}", content_type);
+        }
+    }
+
+    /// A response as read from a server that sent it with `Content-Encoding: coding`.
+    fn received(coding: &str, body: Vec<u8>) -> HttpResponse {
+        let mut response = response("text/plain", body);
+        response.header.headers.insert("Content-Encoding".to_string(), coding.to_string());
+        response
+    }
+
+    #[test]
+    fn decompress_refuses_bodies_over_the_limit() {
+        let body = text(1000);
+
+        let mut over = received("gzip", ContentCoding::Gzip.encode(&body).unwrap());
+        assert!(matches!(decompress_response(&mut over, 999), Err(HttpError::BodyTooLarge(999))));
+
+        let mut at_limit = received("deflate", ContentCoding::Deflate.encode(&body).unwrap());
+        decompress_response(&mut at_limit, 1000).unwrap();
+
+        assert_eq!(encoded(&at_limit), None);
+        assert_eq!(at_limit.header.content_length, 1000);
+        assert_eq!(at_limit.body.unwrap(), body);
+    }
+
+    #[test]
+    fn decompress_takes_raw_deflate_and_refuses_unknown_codings() {
+        let body = text(500);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+
+        let mut raw = received("deflate", encoder.finish().unwrap());
+        decompress_response(&mut raw, 500).unwrap();
+        assert_eq!(raw.body.unwrap(), body);
+
+        assert!(decompress_response(&mut received("br", body), 500).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::http::common::{add_vary, HttpRequest, HttpResponse, HttpStatus, HttpVerb};
+use crate::http::middleware::{Middleware, RequestInfo};
+use crate::http::router::RouteResult;
+
+/// Which browser origins may call the api, and what they may send and read.
+#[derive(Clone)]
+pub struct CorsSettings {
+    /// Origins such as `https://tools.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<HttpVerb>,
+    /// Request headers allowed beyond those every browser may send, or `*` for any.
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read beyond those every browser shows them.
+    pub exposed_headers: Vec<String>,
+    /// Let browsers send cookies and http authentication, and show the response to scripts that did.
+    /// Only for origins listed by name, an origin let in by `*` is never sent credentials.
+    pub allow_credentials: bool,
+    /// How long a browser may reuse the answer to a preflight request.
+    pub max_age: Option<Duration>,
+}
+
+/// Answers CORS preflight requests and marks the responses to allowed origins as readable by them.
+pub(crate) struct CorsMiddleware {
+    settings: CorsSettings,
+}
+
+impl CorsSettings {
+    /// Let `allowed_origins` call the api with the default methods and headers.
+    pub fn create(allowed_origins: Vec<String>) -> CorsSettings {
+        CorsSettings { allowed_origins, ..CorsSettings::default() }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*") || self.lists_origin(origin)
+    }
+
+    /// Check if `origin` is allowed by name, rather than by `*`.
+    fn lists_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|o| o != "*" && o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods.iter().any(|m| m.get_str().eq_ignore_ascii_case(method.trim()))
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        self.allowed_headers.iter().any(|h| h == "*" || h.eq_ignore_ascii_case(header.trim()))
+    }
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        CorsSettings {
+            allowed_origins: vec![],
+            allowed_methods: vec![HttpVerb::Get, HttpVerb::Head, HttpVerb::Post, HttpVerb::Put, HttpVerb::Delete],
+            allowed_headers: vec![
+                "Authorization".to_string(),
+                "Content-Type".to_string(),
+                "X-Api-Key".to_string(),
+                "X-Request-Id".to_string(),
+                "Last-Event-ID".to_string(),
+            ],
+            exposed_headers: vec!["X-Request-Id".to_string(), "Retry-After".to_string()],
+            allow_credentials: false,
+            max_age: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+impl CorsMiddleware {
+    pub fn create(settings: CorsSettings) -> CorsMiddleware {
+        CorsMiddleware { settings }
+    }
+
+    /// Answer a preflight request, which asks if the request it describes may be sent.
+    /// Anything not allowed is refused outright, rather than left to the browser to notice.
+    fn preflight(&self, origin: &str, method: &str, request: &HttpRequest) -> RouteResult {
+        let requested_headers: Vec<&str> = request.header.headers.get("ACCESS-CONTROL-REQUEST-HEADERS")
+            .map(|h| h.split(',').map(|h| h.trim()).filter(|h| !h.is_empty()).collect())
+            .unwrap_or_default();
+
+        if !self.settings.allows_origin(origin) || !self.settings.allows_method(method) || !requested_headers.iter().all(|h| self.settings.allows_header(h)) {
+            let body = Some("Cross-origin request not allowed.".as_bytes().to_vec());
+            return RouteResult::create(HttpResponse::create(HttpStatus::Forbidden, "text/plain".to_string(), HashMap::new(), body));
+        }
+
+        let mut headers = HashMap::new();
+        self.allow_origin(&mut headers, origin);
+
+        let methods: Vec<&str> = self.settings.allowed_methods.iter().map(|m| m.get_str()).collect();
+        headers.insert("Access-Control-Allow-Methods".to_string(), methods.join(", "));
+
+        if !requested_headers.is_empty() {
+            // Listing what was asked for works with credentials, where a literal `*` does not.
+            headers.insert("Access-Control-Allow-Headers".to_string(), requested_headers.join(", "));
+        }
+
+        if let Some(max_age) = self.settings.max_age {
+            headers.insert("Access-Control-Max-Age".to_string(), max_age.as_secs().to_string());
+        }
+
+        add_vary(&mut headers, "Access-Control-Request-Method");
+        add_vary(&mut headers, "Access-Control-Request-Headers");
+
+        let mut response = HttpResponse::create(HttpStatus::NoContent, "text/plain".to_string(), headers, None);
+        response.header.headers.remove("Content-Length");
+
+        RouteResult::create(response)
+    }
+
+    fn allow_origin(&self, headers: &mut HashMap<String, String>, origin: &str) {
+        // Credentials are only shown to an origin listed by name, never to one let in by `*`.
+        match self.settings.lists_origin(origin) {
+            true => {
+                headers.insert("Access-Control-Allow-Origin".to_string(), origin.to_string());
+
+                if self.settings.allow_credentials {
+                    headers.insert("Access-Control-Allow-Credentials".to_string(), "true".to_string());
+                }
+            }
+            false => {
+                headers.insert("Access-Control-Allow-Origin".to_string(), "*".to_string());
+            }
+        }
+
+        // The answer is the same for every origin only when `*` is all that is allowed.
+        if self.settings.allowed_origins.iter().any(|o| o != "*") {
+            add_vary(headers, "Origin");
+        }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn before(&self, request: &mut HttpRequest) -> Option<RouteResult> {
+        let headers = &request.header.headers;
+
+        match (request.header.verb, headers.get("ORIGIN"), headers.get("ACCESS-CONTROL-REQUEST-METHOD")) {
+            (HttpVerb::Options, Some(origin), Some(method)) => Some(self.preflight(origin, method, request)),
+            // Any other `OPTIONS` request is an ordinary one, left to the router.
+            _ => None
+        }
+    }
+
+    fn after(&self, request: &RequestInfo, result: &mut RouteResult) {
+        let origin = match request.headers.get("ORIGIN") {
+            Some(origin) => origin,
+            None => return
+        };
+
+        // Preflight requests were answered by `before`.
+        if is_preflight(request.verb, &request.headers) || !self.settings.allows_origin(origin) {
+            return;
+        }
+
+        let headers = &mut result.response.header.headers;
+
+        self.allow_origin(headers, origin);
+
+        if !self.settings.exposed_headers.is_empty() {
+            headers.insert("Access-Control-Expose-Headers".to_string(), self.settings.exposed_headers.join(", "));
+        }
+    }
+}
+
+fn is_preflight(verb: HttpVerb, headers: &HashMap<String, String>) -> bool {
+    verb == HttpVerb::Options && headers.contains_key("ORIGIN") && headers.contains_key("ACCESS-CONTROL-REQUEST-METHOD")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::http::common::HttpRequestHeader;
+    use super::*;
+
+    fn request(verb: &str, headers: &[&str]) -> HttpRequest {
+        let mut lines = vec![format!("{} /nodes HTTP/1.1", verb)];
+        lines.extend(headers.iter().map(|h| h.to_string()));
+
+        HttpRequest {
+            header: HttpRequestHeader::parse_from_string(lines.join("\r\n")).unwrap(),
+            body: None,
+            remote_address: None,
+            principal: None,
+            received: Instant::now(),
+        }
+    }
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> CorsMiddleware {
+        let origins = origins.iter().map(|o| o.to_string()).collect();
+        CorsMiddleware::create(CorsSettings { allow_credentials, ..CorsSettings::create(origins) })
+    }
+
+    fn preflight(cors: &CorsMiddleware, origin: &str, method: &str, headers: &str) -> RouteResult {
+        let mut request = request("OPTIONS", &[
+            &format!("Origin: {}", origin),
+            &format!("Access-Control-Request-Method: {}", method),
+            &format!("Access-Control-Request-Headers: {}", headers),
+        ]);
+
+        cors.before(&mut request).expect("a preflight is answered")
+    }
+
+    /// The headers added to an ordinary response for a request from `origin`.
+    fn response_headers(cors: &CorsMiddleware, origin: &str) -> HashMap<String, String> {
+        let request = request("GET", &[&format!("Origin: {}", origin)]);
+        let mut result = RouteResult::create(HttpResponse::create(HttpStatus::Ok, "text/plain".to_string(), HashMap::new(), None));
+
+        cors.after(&RequestInfo::from_request(&request, Instant::now()), &mut result);
+        result.response.header.headers
+    }
+
+    #[test]
+    fn preflight_allows_listed_origin_method_and_headers() {
+        let cors = cors(&["https://tools.example.com/"], false);
+        let result = preflight(&cors, "https://TOOLS.example.com", "PUT", "content-type, x-api-key");
+        let headers = &result.response.header.headers;
+
+        assert_eq!(result.response.header.status, HttpStatus::NoContent);
+        assert_eq!(headers["Access-Control-Allow-Origin"], "https://TOOLS.example.com");
+        assert_eq!(headers["Access-Control-Allow-Methods"], "GET, HEAD, POST, PUT, DELETE");
+        assert_eq!(headers["Access-Control-Allow-Headers"], "content-type, x-api-key");
+        assert_eq!(headers["Access-Control-Max-Age"], "600");
+        assert!(headers["Vary"].contains("Origin"));
+        assert!(!headers.contains_key("Access-Control-Allow-Credentials"));
+    }
+
+    #[test]
+    fn preflight_refuses_anything_not_allowed() {
+        let cors = cors(&["https://tools.example.com"], false);
+
+        assert_eq!(preflight(&cors, "https://evil.example.com", "GET", "").response.header.status, HttpStatus::Forbidden);
+        assert_eq!(preflight(&cors, "https://tools.example.com.evil.example", "GET", "").response.header.status, HttpStatus::Forbidden);
+        assert_eq!(preflight(&cors, "https://tools.example.com", "PATCH", "").response.header.status, HttpStatus::Forbidden);
+        assert_eq!(preflight(&cors, "https://tools.example.com", "GET", "X-Custom").response.header.status, HttpStatus::Forbidden);
+    }
+
+    #[test]
+    fn options_without_a_requested_method_is_left_to_the_router() {
+        let mut request = request("OPTIONS", &["Origin: https://tools.example.com"]);
+
+        assert!(cors(&["*"], false).before(&mut request).is_none());
+    }
+
+    #[test]
+    fn wildcard_answers_any_origin_with_star() {
+        let headers = response_headers(&cors(&["*"], false), "https://any.example.com");
+
+        assert_eq!(headers["Access-Control-Allow-Origin"], "*");
+        assert_eq!(headers["Access-Control-Expose-Headers"], "X-Request-Id, Retry-After");
+        assert!(!headers.contains_key("Vary"));
+    }
+
+    #[test]
+    fn credentials_are_only_sent_to_listed_origins() {
+        let cors = cors(&["https://tools.example.com", "*"], true);
+
+        let listed = response_headers(&cors, "https://tools.example.com");
+        assert_eq!(listed["Access-Control-Allow-Origin"], "https://tools.example.com");
+        assert_eq!(listed["Access-Control-Allow-Credentials"], "true");
+
+        let other = response_headers(&cors, "https://evil.example.com");
+        assert_eq!(other["Access-Control-Allow-Origin"], "*");
+        assert!(!other.contains_key("Access-Control-Allow-Credentials"));
+        assert!(other["Vary"].contains("Origin"));
+
+        let result = preflight(&cors, "https://evil.example.com", "POST", "");
+        assert!(!result.response.header.headers.contains_key("Access-Control-Allow-Credentials"));
+    }
+
+    #[test]
+    fn unlisted_origins_get_no_cors_headers() {
+        let headers = response_headers(&cors(&["https://tools.example.com"], true), "https://evil.example.com");
+
+        assert!(!headers.keys().any(|k| k.starts_with("Access-Control-")));
+    }
+}
@@ -0,0 +1,77 @@
+﻿use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// Reading from or writing to the stream failed.
+    Io(std::io::Error),
+    /// The stream was closed before a full request or response was read.
+    ConnectionClosed,
+    /// A read or write did not complete in time.
+    Timeout,
+    /// The server could not be connected to.
+    Connection(&'static str),
+    MalformedRequestLine(String),
+    MalformedStatusLine(String),
+    MalformedChunk,
+    UnsupportedVerb(String),
+    InvalidStatusCode(i16),
+    /// The header block is larger than the header buffer.
+    HeaderTooLarge,
+    /// More headers were sent than the configured limit.
+    TooManyHeaders(usize),
+    /// The body is larger than the configured limit.
+    BodyTooLarge(usize),
+    /// A response was read but its content could not be used.
+    InvalidResponse(&'static str),
+    Serialization(&'static str),
+    /// Setting up TLS failed, such as a missing certificate file or an invalid server name.
+    Tls(String),
+    /// A WebSocket peer broke the protocol.
+    WebSocket(&'static str),
+    /// The log could not be written to, such as once it has stopped.
+    Log(&'static str),
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Io(e) => write!(f, "I/O error - {}", e),
+            HttpError::ConnectionClosed => write!(f, "Connection closed before the message was received."),
+            HttpError::Timeout => write!(f, "Timed out."),
+            HttpError::Connection(message) => write!(f, "{}", message),
+            HttpError::MalformedRequestLine(line) => write!(f, "Malformed request line `{}`.", line),
+            HttpError::MalformedStatusLine(line) => write!(f, "Malformed status line `{}`.", line),
+            HttpError::MalformedChunk => write!(f, "Malformed chunk in chunked body."),
+            HttpError::UnsupportedVerb(verb) => write!(f, "Unsupported http verb `{}`.", verb),
+            HttpError::InvalidStatusCode(code) => write!(f, "Invalid status code {}.", code),
+            HttpError::HeaderTooLarge => write!(f, "Header larger than buffer."),
+            HttpError::TooManyHeaders(limit) => write!(f, "More than the maximum of {} headers.", limit),
+            HttpError::BodyTooLarge(limit) => write!(f, "Body larger than maximum body size of {} bytes.", limit),
+            HttpError::InvalidResponse(message) => write!(f, "{}", message),
+            HttpError::Serialization(message) => write!(f, "{}", message),
+            HttpError::Tls(message) => write!(f, "TLS error - {}", message),
+            HttpError::WebSocket(message) => write!(f, "WebSocket error - {}", message),
+            HttpError::Log(message) => write!(f, "Log error - {}", message),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HttpError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for HttpError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => HttpError::Timeout,
+            ErrorKind::UnexpectedEof => HttpError::ConnectionClosed,
+            _ => HttpError::Io(error)
+        }
+    }
+}
@@ -0,0 +1,201 @@
+﻿use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use crate::{HttpResponse, Logger};
+use crate::common::Principal;
+use crate::http::common::{HttpRequest, HttpStatus, HttpVerb};
+use crate::http::router::RouteResult;
+
+/// A step in the request pipeline that runs around the router.
+pub(crate) trait Middleware: Send + Sync {
+    /// Inspect or decorate the request before it is routed.
+    /// Returning a result answers the request straight away and skips the rest of the chain and the router.
+    fn before(&self, _request: &mut HttpRequest) -> Option<RouteResult> {
+        None
+    }
+
+    /// Decorate the result on its way back out. Called in reverse order for every middleware whose `before` ran.
+    fn after(&self, _request: &RequestInfo, _result: &mut RouteResult) {}
+}
+
+/// The parts of a request still available once it has been handed to the router.
+pub(crate) struct RequestInfo {
+    pub verb: HttpVerb,
+    pub route: String,
+    pub http_version: String,
+    pub headers: HashMap<String, String>,
+    pub remote_address: Option<String>,
+    pub principal: Option<Principal>,
+    /// When the request had been read from the connection.
+    pub started: Instant,
+}
+
+pub(crate) struct MiddlewareChain {
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+/// Adds an `X-Request-Id` to the request and response, keeping one sent by the client.
+pub(crate) struct RequestIdMiddleware;
+
+/// Logs each request with its status and how long it took.
+pub(crate) struct LoggingMiddleware {
+    logger: Logger,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct RateLimit {
+    /// The number of requests allowed in a burst.
+    pub requests: u32,
+    /// The time taken for a full burst allowance to be refilled.
+    pub per: Duration,
+}
+
+/// Limits how often each client address can make requests, answering `429 Too Many Requests` when exceeded.
+pub(crate) struct RateLimitMiddleware {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets are pruned once there are more than this many clients tracked.
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+impl RequestInfo {
+    pub fn from_request(request: &HttpRequest, started: Instant) -> RequestInfo {
+        RequestInfo {
+            verb: request.header.verb,
+            route: request.header.route.clone(),
+            http_version: request.header.http_version.clone(),
+            headers: request.header.headers.clone(),
+            remote_address: request.remote_address.clone(),
+            principal: request.principal.clone(),
+            started,
+        }
+    }
+}
+
+impl MiddlewareChain {
+    pub fn create() -> MiddlewareChain {
+        MiddlewareChain { middleware: Vec::new() }
+    }
+
+    pub fn add<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Run the request through the chain, calling `next` (the router) if no middleware answered it.
+    pub fn handle<F>(&self, mut request: HttpRequest, next: F) -> RouteResult where
+        F: FnOnce(HttpRequest) -> RouteResult,
+    {
+        let started = request.received;
+        let mut ran = 0;
+        let mut short_circuit = None;
+
+        for middleware in &self.middleware {
+            ran += 1;
+
+            if let Some(result) = middleware.before(&mut request) {
+                short_circuit = Some(result);
+                break;
+            }
+        }
+
+        let info = RequestInfo::from_request(&request, started);
+
+        let mut result = match short_circuit {
+            Some(result) => result,
+            None => next(request)
+        };
+
+        for middleware in self.middleware[0..ran].iter().rev() {
+            middleware.after(&info, &mut result);
+        }
+
+        result
+    }
+}
+
+impl Middleware for RequestIdMiddleware {
+    fn before(&self, request: &mut HttpRequest) -> Option<RouteResult> {
+        request.header.headers.entry("X-REQUEST-ID".to_string()).or_insert_with(|| Uuid::new_v4().to_string());
+        None
+    }
+
+    fn after(&self, request: &RequestInfo, result: &mut RouteResult) {
+        if let Some(id) = request.headers.get("X-REQUEST-ID") {
+            result.response.header.headers.insert("X-Request-Id".to_string(), id.clone());
+        }
+    }
+}
+
+impl LoggingMiddleware {
+    pub fn create(logger: Logger) -> LoggingMiddleware {
+        LoggingMiddleware { logger }
+    }
+}
+
+impl Middleware for LoggingMiddleware {
+    fn after(&self, request: &RequestInfo, result: &mut RouteResult) {
+        self.logger.log_info(format!(
+            "{} {} {} {} - {} {} ({} ms)",
+            request.remote_address.as_deref().unwrap_or("-"),
+            request.principal.as_ref().map(|p| p.name.as_str()).unwrap_or("-"),
+            request.verb.get_str(),
+            request.route,
+            result.response.header.status.get_code(),
+            result.response.header.status.get_str(),
+            request.started.elapsed().as_millis())).unwrap();
+    }
+}
+
+impl RateLimitMiddleware {
+    pub fn create(limit: RateLimit) -> RateLimitMiddleware {
+        RateLimitMiddleware {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.limit.requests as f64 / self.limit.per.as_secs_f64().max(0.001)
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn before(&self, request: &mut HttpRequest) -> Option<RouteResult> {
+        let client = request.remote_address.clone().unwrap_or_default();
+        let capacity = self.limit.requests as f64;
+        let rate = self.refill_rate();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_CLIENTS {
+            // Full buckets hold no state worth keeping.
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+
+        let bucket = buckets.entry(client).or_insert(TokenBucket { tokens: capacity, updated: now });
+
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return None;
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64;
+
+        let mut headers = HashMap::new();
+        headers.insert("Retry-After".to_string(), retry_after.to_string());
+
+        let body = Some("Too many requests.".as_bytes().to_vec());
+        Some(RouteResult::create(HttpResponse::create(HttpStatus::TooManyRequests, "text/plain".to_string(), headers, body)))
+    }
+}
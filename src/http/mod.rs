@@ -1,3 +1,16 @@
 ﻿pub mod server;
 pub mod client;
 pub mod common;
+pub mod error;
+pub mod router;
+pub mod middleware;
+pub mod url;
+pub mod tls;
+pub mod auth;
+pub mod sse;
+pub mod websocket;
+pub mod static_files;
+pub mod compression;
+pub mod reactor;
+pub mod access_log;
+pub mod cors;
@@ -0,0 +1,991 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use uuid::Uuid;
+use crate::{HttpResponse, Log, Logger};
+use crate::common::queue::{bounded, OverflowPolicy, QueueReceiver, QueueSender, QueueSettings};
+use crate::common::shutdown::{join_until, ShutdownSignal};
+use crate::http::common::{ChunkedScan, HttpRequest, HttpRequestHeader, HttpStatus, RequestLimits};
+use crate::http::error::HttpError;
+use crate::http::router::RouteResult;
+use crate::http::server::HttpServerSettings;
+use crate::http::tls::NetworkStream;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// Connections are given tokens from here on.
+const FIRST_CONNECTION: usize = 2;
+
+/// The most readiness events handled in one turn of the reactor.
+const EVENT_CAPACITY: usize = 1024;
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How long a client is asked to wait before retrying when the controller is too busy.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Answers a request. Called on a worker thread, so it may block.
+pub(crate) type RequestHandler = Arc<dyn Fn(HttpRequest, &Logger) -> RouteResult + Send + Sync + 'static>;
+
+/// Waits on every connection from one thread, reading requests and writing responses as the sockets allow.
+/// Complete requests are handed to a pool of workers, so a slow route only holds up its own connection.
+pub(crate) struct Reactor {
+    thread: JoinHandle<()>,
+    waker: Arc<Waker>,
+    stopping: ShutdownSignal,
+}
+
+/// The state owned by the reactor thread.
+struct ReactorLoop {
+    poll: Poll,
+    /// `None` once the server is stopping.
+    listener: Option<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    /// The connection limit was reached, or accepting failed, with connections possibly still waiting.
+    accept_paused: bool,
+    settings: Arc<HttpServerSettings>,
+    tls: Option<Arc<ServerConfig>>,
+    workers: RequestWorkers,
+    completions: Receiver<Completion>,
+    /// Connections taken over by an event stream or an upgraded protocol, each on its own thread.
+    handed_off: Vec<JoinHandle<()>>,
+    /// How many of the handed off connections are still open. They count towards `max_connections`.
+    streams: Arc<AtomicUsize>,
+    waker: Arc<Waker>,
+    logger: Logger,
+    stopping: ShutdownSignal,
+}
+
+struct Connection {
+    socket: TcpStream,
+    tls: Option<ServerConnection>,
+    remote: SocketAddr,
+    logger: Logger,
+    settings: Arc<HttpServerSettings>,
+    /// Received and decrypted, but not yet read as a request.
+    input: Vec<u8>,
+    partial: PartialRequest,
+    /// Not yet written to the socket, before encryption.
+    output: Vec<u8>,
+    state: ConnectionState,
+    /// When an idle connection is closed, a request that has not fully arrived is answered with `408`,
+    /// or a client that is not reading the response is dropped.
+    deadline: Instant,
+    requests_handled: usize,
+}
+
+/// What is known of a request that has not all arrived, so nothing is parsed twice as the rest comes in.
+#[derive(Default)]
+struct PartialRequest {
+    /// How far into the input the end of the header has been looked for.
+    scanned: usize,
+    /// The header, once it has all arrived. It has been taken off the input.
+    header: Option<HttpRequestHeader>,
+    chunks: ChunkedScan,
+}
+
+#[derive(Clone, Copy)]
+enum ConnectionState {
+    /// Waiting for the next request to start.
+    Idle,
+    /// Part of a request has arrived.
+    Reading,
+    /// A worker is answering the request.
+    Dispatched { keep_alive: bool },
+    Writing { keep_alive: bool },
+}
+
+/// What the reactor should do with a connection after driving it.
+enum Drive {
+    /// Nothing more can be done until the socket is ready again.
+    Wait,
+    Dispatch(Box<HttpRequest>),
+    Close,
+}
+
+/// Threads that run the request handler.
+struct RequestWorkers {
+    threads: Vec<(usize, JoinHandle<()>)>,
+    sender: QueueSender<Job>,
+    logger: Logger,
+}
+
+struct Job {
+    token: Token,
+    request: HttpRequest,
+    logger: Logger,
+}
+
+struct Completion {
+    token: Token,
+    result: RouteResult,
+}
+
+/// Held by the thread a connection is handed off to, so it is counted as open until the thread is done with it.
+/// Wakes the reactor when dropped, a connection waiting in the backlog may be able to take its place.
+struct StreamGuard {
+    streams: Arc<AtomicUsize>,
+    waker: Arc<Waker>,
+}
+
+impl Reactor {
+    pub fn start(listener: std::net::TcpListener, settings: Arc<HttpServerSettings>, tls: Option<Arc<ServerConfig>>, handler: RequestHandler, log: &Log) -> Result<Reactor, HttpError> {
+        listener.set_nonblocking(true)?;
+
+        let mut listener = TcpListener::from_std(listener);
+        let poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (completion_sender, completions) = channel();
+
+        let queue = QueueSettings::create(settings.max_queued_requests, OverflowPolicy::Reject);
+        let workers = RequestWorkers::new(settings.pool_size, queue, handler, completion_sender, waker.clone(), log);
+
+        let stopping = ShutdownSignal::create();
+
+        let reactor = ReactorLoop {
+            poll,
+            listener: Some(listener),
+            connections: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+            accept_paused: false,
+            settings,
+            tls,
+            workers,
+            completions,
+            handed_off: Vec::new(),
+            streams: Arc::new(AtomicUsize::new(0)),
+            waker: waker.clone(),
+            logger: log.get_logger("http_server".to_string()),
+            stopping: stopping.clone(),
+        };
+
+        let thread = thread::spawn(move || reactor.run());
+
+        Ok(Reactor { thread, waker, stopping })
+    }
+
+    /// Stop accepting connections, close the idle ones and wait for the rest to finish their current request.
+    /// Event streams and WebSockets only end once the `EventHub` is closed.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.stopping.trigger();
+
+        // The reactor may be waiting on sockets that stay quiet.
+        let _ = self.waker.wake();
+
+        join_until(self.thread, deadline)
+    }
+}
+
+impl ReactorLoop {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(EVENT_CAPACITY);
+
+        loop {
+            if self.stopping.is_triggered() && self.listener.is_some() {
+                self.stop_accepting();
+            }
+
+            if self.listener.is_none() && self.connections.is_empty() {
+                break;
+            }
+
+            let timeout = self.expire(Instant::now());
+
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+
+                self.logger.log_error(format!("Could not wait for connections. Error - {}", e)).unwrap();
+                break;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    // Woken for finished requests, which are picked up below, for a closed stream, or to stop.
+                    WAKER => self.released(),
+                    token => self.drive(token),
+                }
+            }
+
+            self.complete();
+        }
+
+        // Let the requests being handled finish.
+        self.workers.shutdown();
+
+        for thread in self.handed_off {
+            let _ = thread.join();
+        }
+
+        self.logger.log_info("Stopped".to_string()).unwrap();
+    }
+
+    fn accept(&mut self) {
+        while self.open_connections() < self.settings.max_connections {
+            let accepted = match &self.listener {
+                Some(listener) => listener.accept(),
+                None => return
+            };
+
+            let (mut socket, remote) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.accept_paused = false;
+                    return;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Such as running out of file descriptors, tried again once a connection closes.
+                    self.logger.log_error(format!("Could not accept connection. Error - {}", e)).unwrap();
+                    self.accept_paused = true;
+                    return;
+                }
+            };
+
+            self.logger.log_info(format!("Request received from {}", remote)).unwrap();
+
+            let tls = match &self.tls {
+                Some(config) => match ServerConnection::new(config.clone()) {
+                    Ok(connection) => Some(connection),
+                    Err(e) => {
+                        self.logger.log_error(format!("Could not set up connection. Error - {}", e)).unwrap();
+                        continue;
+                    }
+                },
+                None => None
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            // Edge triggered, so being told the socket is writable while nothing is waiting to be written costs little.
+            if let Err(e) = self.poll.registry().register(&mut socket, token, Interest::READABLE | Interest::WRITABLE) {
+                self.logger.log_error(format!("Could not register connection. Error - {}", e)).unwrap();
+                continue;
+            }
+
+            let slug = String::from(Uuid::new_v4().to_string().split_at(6).0);
+
+            self.connections.insert(token, Connection {
+                socket,
+                tls,
+                remote,
+                logger: self.logger.create_from(slug),
+                settings: self.settings.clone(),
+                input: Vec::new(),
+                partial: PartialRequest::default(),
+                output: Vec::new(),
+                state: ConnectionState::Idle,
+                deadline: Instant::now() + self.settings.keep_alive_timeout,
+                requests_handled: 0,
+            });
+
+            // Part of the request may already be waiting.
+            self.drive(token);
+        }
+
+        // Left waiting in the backlog until a connection closes.
+        if !self.accept_paused {
+            self.logger.log_warning(format!("Reached the limit of {} connections", self.settings.max_connections)).unwrap();
+            self.accept_paused = true;
+        }
+    }
+
+    fn stop_accepting(&mut self) {
+        if let Some(mut listener) = self.listener.take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+            self.logger.log_info("No longer accepting connections".to_string()).unwrap();
+        }
+
+        let idle: Vec<Token> = self.connections.iter()
+            .filter(|(_, c)| matches!(c.state, ConnectionState::Idle) && c.input.is_empty())
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn drive(&mut self, token: Token) {
+        let stopping = self.stopping.is_triggered();
+
+        let next = match self.connections.get_mut(&token) {
+            Some(connection) => connection.drive(stopping),
+            None => return
+        };
+
+        match next {
+            Drive::Wait => {}
+            Drive::Dispatch(request) => self.dispatch(token, *request),
+            Drive::Close => self.close(token),
+        }
+    }
+
+    fn dispatch(&mut self, token: Token, mut request: HttpRequest) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return
+        };
+
+        request.remote_address = Some(connection.remote.ip().to_string());
+        connection.requests_handled += 1;
+        connection.logger.log_info(format!("Request for route {}. Type: {}", request.header.route, request.header.verb.get_str())).unwrap();
+
+        if let Some(fragment) = &request.header.url.fragment {
+            connection.logger.log_debug(format!("Request target has a fragment ({}), routes only match the path.", fragment)).unwrap();
+        }
+
+        let keep_alive = connection.keep_alive(&request);
+        let job = Job { token, request, logger: connection.logger.clone() };
+
+        match self.workers.sender.send(job) {
+            Ok(_) => connection.state = ConnectionState::Dispatched { keep_alive },
+            Err(e) => {
+                connection.logger.log_warning(format!("Request from {} refused - {}", connection.remote, e)).unwrap();
+                connection.respond(unavailable(), false);
+                self.drive(token);
+            }
+        }
+    }
+
+    /// Send the responses the workers have finished.
+    fn complete(&mut self) {
+        let stopping = self.stopping.is_triggered();
+
+        while let Ok(Completion { token, result }) = self.completions.try_recv() {
+            let connection = match self.connections.get_mut(&token) {
+                Some(connection) => connection,
+                None => continue
+            };
+
+            let keep_alive = match connection.state {
+                ConnectionState::Dispatched { keep_alive } => keep_alive && !stopping,
+                _ => continue
+            };
+
+            if result.upgrade.is_some() || result.response.stream.is_some() {
+                if self.streams.load(Ordering::SeqCst) < self.settings.max_streams {
+                    self.hand_off(token, result);
+                    continue;
+                }
+
+                connection.logger.log_warning(format!("Reached the limit of {} event streams and WebSockets", self.settings.max_streams)).unwrap();
+                connection.respond(unavailable(), false);
+                self.drive(token);
+                continue;
+            }
+
+            connection.respond(result.response, keep_alive);
+            self.drive(token);
+        }
+    }
+
+    /// Give a connection to a thread of its own, for responses that are written for as long as the client listens
+    /// and for protocols that take over once the response is sent.
+    fn hand_off(&mut self, token: Token, mut result: RouteResult) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return
+        };
+
+        // Still counted as open until the thread it is handed to is done with it.
+        let _ = self.poll.registry().deregister(&mut connection.socket);
+
+        let Connection { socket, tls, logger, settings, .. } = connection;
+
+        let socket = std::net::TcpStream::from(socket);
+        let blocking = socket.set_nonblocking(false)
+            .and_then(|_| socket.set_write_timeout(Some(settings.write_timeout)))
+            .and_then(|_| socket.set_read_timeout(Some(settings.request_timeout)));
+
+        if let Err(e) = blocking {
+            logger.log_error(format!("Could not hand off connection. Error - {}", e)).unwrap();
+            self.released();
+            return;
+        }
+
+        let stream = match tls {
+            Some(tls) => NetworkStream::ServerTls(Box::new(StreamOwned::new(tls, socket))),
+            None => NetworkStream::Plain(socket)
+        };
+
+        // Nothing can follow a body that only ends with the connection.
+        set_connection_headers(&mut result.response, false, settings.keep_alive_timeout);
+
+        self.streams.fetch_add(1, Ordering::SeqCst);
+        let guard = StreamGuard { streams: self.streams.clone(), waker: self.waker.clone() };
+
+        self.handed_off.retain(|thread| !thread.is_finished());
+        self.handed_off.push(thread::spawn(move || {
+            let _guard = guard;
+            take_over(stream, result, logger);
+        }));
+    }
+
+    /// Connections waited on by the reactor and those handed off to threads of their own.
+    fn open_connections(&self) -> usize {
+        self.connections.len() + self.streams.load(Ordering::SeqCst)
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.socket);
+            connection.close();
+            self.released();
+        }
+    }
+
+    /// A connection has gone, so one waiting in the backlog can take its place.
+    fn released(&mut self) {
+        if self.accept_paused {
+            self.accept();
+        }
+    }
+
+    /// Deal with the connections whose deadline has passed.
+    /// Returns how long until the next one does, `None` if there are none to wait for.
+    fn expire(&mut self, now: Instant) -> Option<Duration> {
+        let expired: Vec<Token> = self.connections.iter()
+            .filter(|(_, c)| !matches!(c.state, ConnectionState::Dispatched { .. }) && c.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            let connection = match self.connections.get_mut(&token) {
+                Some(connection) => connection,
+                None => continue
+            };
+
+            match connection.state {
+                ConnectionState::Reading => {
+                    connection.logger.log_error(format!("Could not get request. Error - {}", HttpError::Timeout)).unwrap();
+                    connection.respond(request_error_response(&HttpError::Timeout).unwrap(), false);
+                    self.drive(token);
+                }
+                ConnectionState::Writing { .. } => {
+                    connection.logger.log_error("Client is not reading the response, dropping connection.".to_string()).unwrap();
+                    self.close(token);
+                }
+                _ => {
+                    connection.logger.log_debug("Keep-alive timeout reached.".to_string()).unwrap();
+                    self.close(token);
+                }
+            }
+        }
+
+        self.connections.values()
+            .filter(|c| !matches!(c.state, ConnectionState::Dispatched { .. }))
+            .map(|c| c.deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+}
+
+impl Connection {
+    /// Read, parse and write as far as the socket allows.
+    fn drive(&mut self, stopping: bool) -> Drive {
+        loop {
+            match self.state {
+                ConnectionState::Dispatched { .. } => return Drive::Wait,
+                ConnectionState::Writing { keep_alive } => {
+                    match self.flush() {
+                        Ok(true) => {}
+                        Ok(false) => return Drive::Wait,
+                        Err(e) => {
+                            self.logger.log_error(format!("Error sending response - {}", e)).unwrap();
+                            return Drive::Close;
+                        }
+                    }
+
+                    self.logger.log_success("Response sent.".to_string()).unwrap();
+
+                    if !keep_alive {
+                        return Drive::Close;
+                    }
+
+                    self.state = ConnectionState::Idle;
+                    self.deadline = Instant::now() + self.settings.keep_alive_timeout;
+                }
+                ConnectionState::Idle | ConnectionState::Reading => {
+                    let closed = match self.receive() {
+                        Ok(closed) => closed,
+                        Err(e) => {
+                            self.logger.log_error(format!("Could not get request. Error - {}", e)).unwrap();
+                            return Drive::Close;
+                        }
+                    };
+
+                    // A TLS handshake has its own records to send back.
+                    if let Err(e) = self.flush() {
+                        self.logger.log_error(format!("Could not get request. Error - {}", e)).unwrap();
+                        return Drive::Close;
+                    }
+
+                    if self.input.is_empty() {
+                        return match closed || stopping {
+                            true => Drive::Close,
+                            false => Drive::Wait
+                        };
+                    }
+
+                    if let ConnectionState::Idle = self.state {
+                        self.state = ConnectionState::Reading;
+                        self.deadline = Instant::now() + self.settings.request_timeout;
+                    }
+
+                    match self.parse() {
+                        Ok(Some(request)) => return Drive::Dispatch(Box::new(request)),
+                        // The client went away part way through.
+                        Ok(None) if closed => return Drive::Close,
+                        Ok(None) => return Drive::Wait,
+                        Err(e) => {
+                            self.logger.log_error(format!("Could not get request. Error - {}", e)).unwrap();
+
+                            // The rest of the request is not read, so the connection is closed.
+                            match request_error_response(&e) {
+                                Some(response) => self.respond(response, false),
+                                None => return Drive::Close
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read what the client has sent. Returns true if it has closed its side.
+    fn receive(&mut self) -> std::io::Result<bool> {
+        let limit = self.max_request_size();
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+
+        // Stopping short of the limit leaves the rest for once the request in hand is answered.
+        while self.input.len() < limit {
+            match &mut self.tls {
+                None => match self.socket.read(&mut buffer) {
+                    Ok(0) => return Ok(true),
+                    Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e)
+                },
+                Some(tls) => {
+                    match tls.read_tls(&mut self.socket) {
+                        Ok(0) => return Ok(true),
+                        Ok(_) => {}
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e)
+                    }
+
+                    let state = tls.process_new_packets().map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+                    let start = self.input.len();
+
+                    self.input.resize(start + state.plaintext_bytes_to_read(), 0);
+                    tls.reader().read_exact(&mut self.input[start..])?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Write as much of the output as the socket takes. Returns true once all of it is written.
+    fn flush(&mut self) -> std::io::Result<bool> {
+        let mut written = false;
+
+        let flushed = loop {
+            match &mut self.tls {
+                None => {
+                    if self.output.is_empty() {
+                        break true;
+                    }
+
+                    match self.socket.write(&self.output) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(length) => {
+                            self.output.drain(..length);
+                            written = true;
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e)
+                    }
+                }
+                Some(tls) => {
+                    if !self.output.is_empty() {
+                        let length = tls.writer().write(&self.output)?;
+                        self.output.drain(..length);
+                    }
+
+                    if !tls.wants_write() {
+                        break self.output.is_empty();
+                    }
+
+                    match tls.write_tls(&mut self.socket) {
+                        Ok(_) => written = true,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+        };
+
+        // A client reading slowly is fine, one that stops reading is not.
+        if written && matches!(self.state, ConnectionState::Writing { .. }) {
+            self.deadline = Instant::now() + self.settings.write_timeout;
+        }
+
+        Ok(flushed)
+    }
+
+    /// Read a request from the input, `None` if not all of it has arrived.
+    /// The header is parsed once it is all there, the body once the header says it is all there.
+    fn parse(&mut self) -> Result<Option<HttpRequest>, HttpError> {
+        let limits = RequestLimits {
+            max_header_size: self.settings.max_header_size,
+            max_header_count: self.settings.max_header_count,
+            max_body_size: self.settings.max_body_size,
+        };
+
+        let header = match self.partial.header.take() {
+            Some(header) => header,
+            None => {
+                let end = match find_header_end(&self.input, self.partial.scanned) {
+                    Some(end) => end,
+                    None if self.input.len() >= limits.max_header_size => return Err(HttpError::HeaderTooLarge),
+                    None => {
+                        // The blank line may be split between this read and the next.
+                        self.partial.scanned = self.input.len().saturating_sub(3);
+                        return Ok(None);
+                    }
+                };
+
+                self.logger.log_debug("Parsing http request header.".to_string()).map_err(HttpError::Log)?;
+
+                let header = HttpRequestHeader::read_from_stream(&mut Cursor::new(&self.input[..end]), &limits)?;
+                self.input.drain(..end);
+                header
+            }
+        };
+
+        let arrived = match header.chunked {
+            true => self.partial.chunks.advance(&self.input, limits.max_body_size),
+            // A body over the limit is refused without waiting for it.
+            false => header.content_length > limits.max_body_size || self.input.len() >= header.content_length
+        };
+
+        if !arrived {
+            self.partial.header = Some(header);
+
+            // The framing of a chunked body can take it past the limit even when the body itself is not.
+            return match self.input.len() >= self.max_request_size() {
+                true => Err(HttpError::BodyTooLarge(limits.max_body_size)),
+                false => Ok(None)
+            };
+        }
+
+        self.partial = PartialRequest::default();
+
+        let mut cursor = Cursor::new(&self.input[..]);
+        let request = HttpRequest::body_from_stream(header, &mut cursor, &limits, &self.logger)?;
+
+        let length = cursor.position() as usize;
+        self.input.drain(..length);
+
+        Ok(Some(request))
+    }
+
+    fn max_request_size(&self) -> usize {
+        self.settings.max_header_size + self.settings.max_body_size
+    }
+
+    /// Check if the connection should stay open after responding to this request.
+    fn keep_alive(&self, request: &HttpRequest) -> bool {
+        let connection = request.header.headers.get("CONNECTION").map(|v| v.to_lowercase());
+
+        let requested = match (request.header.http_version.as_str(), connection) {
+            (_, Some(c)) if c.contains("close") => false,
+            ("HTTP/1.1", _) => true,
+            (_, Some(c)) => c.contains("keep-alive"),
+            (_, None) => false
+        };
+
+        requested && self.requests_handled < self.settings.max_keep_alive_requests
+    }
+
+    /// Queue a response to be written as the socket allows.
+    fn respond(&mut self, mut response: HttpResponse, keep_alive: bool) {
+        set_connection_headers(&mut response, keep_alive, self.settings.keep_alive_timeout);
+
+        self.output.extend(response.to_bytes());
+        self.state = ConnectionState::Writing { keep_alive };
+        self.deadline = Instant::now() + self.settings.write_timeout;
+    }
+
+    /// Tell the peer the connection is being closed. Only TLS has anything to send.
+    fn close(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut self.socket);
+        }
+
+        self.logger.log_info("Connection closed.".to_string()).unwrap();
+    }
+}
+
+impl RequestWorkers {
+    fn new(size: usize, queue: QueueSettings, handler: RequestHandler, completions: Sender<Completion>, waker: Arc<Waker>, log: &Log) -> RequestWorkers {
+        let (sender, receiver) = bounded::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let threads = (0..size)
+            .map(|id| {
+                let logger = log.get_logger(format!("worker_{}", id));
+                logger.log_info("Starting".to_string()).unwrap();
+
+                let thread = RequestWorkers::spawn(receiver.clone(), handler.clone(), completions.clone(), waker.clone());
+                (id, thread)
+            })
+            .collect();
+
+        RequestWorkers {
+            threads,
+            sender,
+            logger: log.get_logger("request_workers".to_string()),
+        }
+    }
+
+    fn spawn(receiver: Arc<Mutex<QueueReceiver<Job>>>, handler: RequestHandler, completions: Sender<Completion>, waker: Arc<Waker>) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break
+            };
+
+            // A panicking route is answered like any other error, rather than taking the worker
+            // and leaving the connection waiting for a response forever.
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| handler(job.request, &job.logger))) {
+                Ok(result) => result,
+                Err(_) => {
+                    job.logger.log_error("Request handler panicked.".to_string()).unwrap();
+                    RouteResult::create(internal_error())
+                }
+            };
+
+            if completions.send(Completion { token: job.token, result }).is_err() {
+                break;
+            }
+
+            let _ = waker.wake();
+        })
+    }
+
+    /// Wait for the workers to finish the requests queued or in progress, then stop them.
+    fn shutdown(self) {
+        drop(self.sender);
+
+        for (id, thread) in self.threads {
+            if thread.join().is_err() {
+                self.logger.log_error(format!("Request worker {} panicked", id)).unwrap();
+            }
+        }
+    }
+}
+
+/// Stop counting the handed off connection as open and wake the reactor, whether its thread returned or panicked.
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Write a response that takes over its connection, then hand the connection to the upgraded protocol if there is one.
+fn take_over(mut stream: NetworkStream, mut result: RouteResult, logger: Logger) {
+    if let Err(e) = result.response.write_to(&mut stream) {
+        logger.log_error(format!("Error sending response - {}", e)).unwrap();
+        stream.close();
+        return;
+    }
+
+    logger.log_success("Response sent.".to_string()).unwrap();
+
+    match result.upgrade {
+        Some(upgrade) => upgrade(BufReader::new(stream), logger),
+        None => {
+            stream.close();
+            logger.log_info("Connection closed.".to_string()).unwrap();
+        }
+    }
+}
+
+/// Where the header in `input` ends, after its blank line, looking from `from` on.
+fn find_header_end(input: &[u8], from: usize) -> Option<usize> {
+    input[from..].windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| from + position + 4)
+}
+
+fn set_connection_headers(response: &mut HttpResponse, keep_alive: bool, keep_alive_timeout: Duration) {
+    match keep_alive {
+        // Switching protocols sets its own `Connection: Upgrade`.
+        _ if response.header.status == HttpStatus::SwitchingProtocols => {}
+        true => {
+            response.header.headers.insert("Connection".to_string(), "keep-alive".to_string());
+            response.header.headers.insert("Keep-Alive".to_string(), format!("timeout={}", keep_alive_timeout.as_secs()));
+        }
+        false => {
+            response.header.headers.insert("Connection".to_string(), "close".to_string());
+        }
+    }
+}
+
+/// The response to a request that could not be read, `None` if the connection is gone.
+fn request_error_response(error: &HttpError) -> Option<HttpResponse> {
+    let (status, message) = match error {
+        HttpError::Timeout => (HttpStatus::RequestTimeout, "Request not received in time."),
+        HttpError::HeaderTooLarge => (HttpStatus::RequestHeaderFieldsTooLarge, "Request header too large."),
+        HttpError::TooManyHeaders(_) => (HttpStatus::RequestHeaderFieldsTooLarge, "Too many request headers."),
+        HttpError::BodyTooLarge(_) => (HttpStatus::PayloadTooLarge, "Request body too large."),
+        HttpError::MalformedRequestLine(_) | HttpError::MalformedChunk | HttpError::UnsupportedVerb(_) => (HttpStatus::BadRequest, "Malformed request."),
+        _ => return None
+    };
+
+    let body = Some(message.as_bytes().to_vec());
+    Some(HttpResponse::create(status, "text/plain".to_string(), HashMap::new(), body))
+}
+
+pub(crate) fn unavailable() -> HttpResponse {
+    let mut headers = HashMap::new();
+    headers.insert("Retry-After".to_string(), RETRY_AFTER.as_secs().to_string());
+
+    let body = Some("Too busy, try again later.".as_bytes().to_vec());
+    HttpResponse::create(HttpStatus::ServiceUnavailable, "text/plain".to_string(), headers, body)
+}
+
+fn internal_error() -> HttpResponse {
+    let body = Some("The request could not be handled.".as_bytes().to_vec());
+    HttpResponse::create(HttpStatus::InternalError, "text/plain".to_string(), HashMap::new(), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use super::*;
+
+    /// A reactor on a free local port, with one worker so a lost worker would stop it answering.
+    fn start(handler: RequestHandler) -> (Reactor, Log, SocketAddr) {
+        let log = Log::start().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let settings = Arc::new(HttpServerSettings { pool_size: 1, ..HttpServerSettings::default() });
+
+        (Reactor::start(listener, settings, None, handler, &log).unwrap(), log, address)
+    }
+
+    fn stop(reactor: Reactor, log: Log) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(reactor.shutdown(deadline));
+        log.shutdown(deadline);
+    }
+
+    /// Send a request in parts, pausing between them, and read everything sent back until the server closes.
+    fn exchange(address: SocketAddr, parts: &[&[u8]]) -> String {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        for part in parts {
+            stream.write_all(part).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn echo_route() -> RequestHandler {
+        Arc::new(|request, _| {
+            let body = format!("{} {}", request.header.route, String::from_utf8_lossy(request.body.as_deref().unwrap_or_default()));
+            RouteResult::create(HttpResponse::create(HttpStatus::Ok, "text/plain".to_string(), HashMap::new(), Some(body.into_bytes())))
+        })
+    }
+
+    #[test]
+    fn requests_split_across_reads_are_put_back_together() {
+        let (reactor, log, address) = start(echo_route());
+
+        let response = exchange(address, &[b"POST /a HT", b"TP/1.1\r\nContent-Length: 11\r\nConnection: close\r\n\r", b"\nhello", b" world"]);
+        assert!(response.starts_with("HTTP/1.1 200 ") && response.ends_with("/a hello world"), "{}", response);
+
+        let response = exchange(address, &[b"POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", b"5\r\nhel", b"lo\r\n", b"6;x=1\r", b"\n world\r\n0\r\n", b"\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 200 ") && response.ends_with("/b hello world"), "{}", response);
+
+        stop(reactor, log);
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let (reactor, log, address) = start(echo_route());
+
+        let response = exchange(address, &[b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nonePOST /b HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\ntwo"]);
+        let first = response.find("/a one").expect(&response);
+        let second = response.find("/b two").expect(&response);
+
+        assert!(first < second);
+        assert_eq!(response.matches("HTTP/1.1 200 ").count(), 2);
+
+        stop(reactor, log);
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_they_arrive() {
+        let (reactor, log, address) = start(echo_route());
+        let request = format!("POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n", HttpServerSettings::default().max_body_size + 1);
+
+        let response = exchange(address, &[request.as_bytes()]);
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        stop(reactor, log);
+    }
+
+    #[test]
+    fn a_panicking_handler_is_answered_with_500() {
+        let (reactor, log, address) = start(Arc::new(|request, _| {
+            if request.header.route == "/panic" {
+                panic!("route failed");
+            }
+
+            RouteResult::create(HttpResponse::create(HttpStatus::Ok, "text/plain".to_string(), HashMap::new(), None))
+        }));
+
+        let response = exchange(address, &[b"GET /panic HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+
+        // The one worker is still there to answer.
+        let response = exchange(address, &[b"GET /ok HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+        stop(reactor, log);
+    }
+}
@@ -0,0 +1,368 @@
+﻿use std::collections::HashMap;
+use std::io::BufReader;
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::{Command, Event, HttpResponse, Logger};
+use crate::common::permissions::{AccessPolicy, Permission};
+use crate::http::auth::error_result;
+use crate::http::common::{HttpRequest, HttpStatus, HttpVerb};
+use crate::http::tls::NetworkStream;
+
+pub(crate) struct RouteResult {
+    pub response: HttpResponse,
+    pub events: Vec<Event>,
+    pub commands: Vec<Command>,
+    /// Takes over the connection once the response has been sent, for protocols such as WebSocket.
+    pub upgrade: Option<UpgradeHandler>,
+}
+
+pub(crate) type UpgradeHandler = Box<dyn FnOnce(BufReader<NetworkStream>, Logger) + Send + 'static>;
+
+/// A route handler. `S` is the state shared by every route, such as channels to the rest of the controller.
+pub(crate) type RouteHandler<S> = Box<dyn Fn(HttpRequest, &PathParams, &S) -> RouteResult + Send + Sync + 'static>;
+
+/// Values captured from the `{name}` segments of a route pattern.
+pub(crate) struct PathParams {
+    values: HashMap<String, String>,
+}
+
+pub(crate) struct Router<S> {
+    routes: Vec<Route<S>>,
+    /// Checked before a route that needs a permission is dispatched, such routes are open to anyone if `None`.
+    access: Option<Arc<AccessPolicy>>,
+}
+
+struct Route<S> {
+    verb: HttpVerb,
+    pattern: Vec<PatternSegment>,
+    permission: Option<Permission>,
+    handler: RouteHandler<S>,
+}
+
+enum PatternSegment {
+    Literal(String),
+    Param(String),
+    /// `{*name}`, the rest of the path joined with `/`. Only allowed as the last segment.
+    CatchAll(String),
+}
+
+impl RouteResult {
+    pub fn create(response: HttpResponse) -> RouteResult {
+        RouteResult {
+            response,
+            events: vec![],
+            commands: vec![],
+            upgrade: None,
+        }
+    }
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| v.as_str())
+    }
+
+    /// Get a parameter parsed as `T`, or `None` if it is missing or does not parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.parse::<T>().ok())
+    }
+}
+
+impl<S> Router<S> {
+    pub fn create() -> Router<S> {
+        Router { routes: Vec::new(), access: None }
+    }
+
+    pub fn set_access_policy(&mut self, access: Arc<AccessPolicy>) {
+        self.access = Some(access);
+    }
+
+    /// Register a handler for a verb and route pattern, such as `(HttpVerb::Get, "/nodes/{name}/state")`.
+    /// A final `{*name}` segment matches the rest of the path, including nothing at all.
+    pub fn add<F>(&mut self, verb: HttpVerb, pattern: &str, handler: F) where
+        F: Fn(HttpRequest, &PathParams, &S) -> RouteResult + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            verb,
+            pattern: parse_pattern(pattern),
+            permission: None,
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Register a handler that needs `permission`. If the pattern has a `{name}` parameter
+    /// the permission must cover that node, otherwise it must cover at least one node.
+    pub fn add_with_permission<F>(&mut self, verb: HttpVerb, pattern: &str, permission: Permission, handler: F) where
+        F: Fn(HttpRequest, &PathParams, &S) -> RouteResult + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            verb,
+            pattern: parse_pattern(pattern),
+            permission: Some(permission),
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Find the handler for a request and call it. Routes are matched against the decoded path segments,
+    /// so path parameters are already percent-decoded.
+    /// If the path matches but the verb does not the result is `405 Method Not Allowed`,
+    /// `HEAD` is answered by the `GET` handler without the body and `OPTIONS` lists the allowed verbs.
+    pub fn route(&self, request: HttpRequest, state: &S) -> RouteResult {
+        let segments = request.header.url.segments.clone();
+
+        let mut allowed: Vec<HttpVerb> = Vec::new();
+        let verb = request.header.verb;
+
+        for route in &self.routes {
+            if let Some(params) = route.matches(&segments) {
+                if route.verb == verb {
+                    if !self.authorized(&request, route.permission, &params) {
+                        return error_result(HttpStatus::Forbidden, "forbidden", "Permission denied.", HashMap::new());
+                    }
+
+                    return (route.handler)(request, &params, state);
+                }
+
+                if !allowed.contains(&route.verb) {
+                    allowed.push(route.verb);
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            return not_found();
+        }
+
+        match verb {
+            HttpVerb::Head if allowed.contains(&HttpVerb::Get) => {
+                let mut request = request;
+                request.header.verb = HttpVerb::Get;
+
+                let mut result = self.route(request, state);
+
+                // Keep the headers (including `Content-Length`) of the GET response but drop the body.
+                result.response.body = None;
+                result.response.stream = None;
+                result
+            }
+            HttpVerb::Options => {
+                let mut headers = HashMap::new();
+                headers.insert("Allow".to_string(), allow_header(allowed));
+
+                RouteResult::create(HttpResponse::create(HttpStatus::NoContent, "text/plain".to_string(), headers, None))
+            }
+            _ => {
+                let mut headers = HashMap::new();
+                headers.insert("Allow".to_string(), allow_header(allowed));
+
+                let body = Some("Method not allowed.".as_bytes().to_vec());
+                RouteResult::create(HttpResponse::create(HttpStatus::MethodNotAllowed, "text/plain".to_string(), headers, body))
+            }
+        }
+    }
+
+    /// Check the request's principal has the route's permission, on the `{name}` node if there is one.
+    fn authorized(&self, request: &HttpRequest, permission: Option<Permission>, params: &PathParams) -> bool {
+        match (&self.access, permission, &request.principal) {
+            (None, _, _) | (_, None, _) => true,
+            (Some(access), Some(permission), Some(principal)) => access.allows(principal, permission, params.get("name")),
+            (Some(_), Some(_), None) => false
+        }
+    }
+}
+
+impl<S> Route<S> {
+    fn matches(&self, segments: &[String]) -> Option<PathParams> {
+        let catch_all = matches!(self.pattern.last(), Some(PatternSegment::CatchAll(_)));
+
+        match catch_all {
+            true if segments.len() < self.pattern.len() - 1 => return None,
+            false if segments.len() != self.pattern.len() => return None,
+            _ => {}
+        }
+
+        let mut values = HashMap::new();
+
+        for (i, pattern) in self.pattern.iter().enumerate() {
+            match pattern {
+                PatternSegment::Literal(literal) if *literal == segments[i] => {}
+                PatternSegment::Literal(_) => return None,
+                PatternSegment::Param(name) => {
+                    values.insert(name.clone(), segments[i].clone());
+                }
+                PatternSegment::CatchAll(name) => {
+                    values.insert(name.clone(), segments[i..].join("/"));
+                }
+            }
+        }
+
+        Some(PathParams { values })
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<PatternSegment> {
+    split_path(pattern)
+        .into_iter()
+        .map(|segment| {
+            match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => match name.strip_prefix('*') {
+                    Some(name) => PatternSegment::CatchAll(name.to_string()),
+                    None => PatternSegment::Param(name.to_string())
+                },
+                None => PatternSegment::Literal(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+/// The `Allow` header value, `HEAD` is implied by `GET` and `OPTIONS` is always handled.
+fn allow_header(mut allowed: Vec<HttpVerb>) -> String {
+    if allowed.contains(&HttpVerb::Get) && !allowed.contains(&HttpVerb::Head) {
+        allowed.push(HttpVerb::Head);
+    }
+
+    if !allowed.contains(&HttpVerb::Options) {
+        allowed.push(HttpVerb::Options);
+    }
+
+    let verbs: Vec<&str> = allowed.iter().map(|v| v.get_str()).collect();
+    verbs.join(", ")
+}
+
+fn not_found() -> RouteResult {
+    let body = Some("Not found".as_bytes().to_vec());
+    RouteResult::create(HttpResponse::create(HttpStatus::NotFound, "text/plain".to_string(), HashMap::new(), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{AuthMethod, Principal};
+    use crate::common::permissions::Grant;
+    use crate::http::common::find_header;
+    use super::*;
+
+    /// Answers with the route's name followed by its parameters, such as `state name=node1`.
+    fn reply(name: &'static str) -> impl Fn(HttpRequest, &PathParams, &()) -> RouteResult {
+        move |_, params, _| {
+            let mut values: Vec<String> = params.values.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            values.sort();
+            values.insert(0, name.to_string());
+
+            RouteResult::create(HttpResponse::create(HttpStatus::Ok, "text/plain".to_string(), HashMap::new(), Some(values.join(" ").into_bytes())))
+        }
+    }
+
+    fn route(router: &Router<()>, verb: HttpVerb, path: &str) -> RouteResult {
+        router.route(HttpRequest::create(path.to_string(), verb, "text/plain".to_string(), HashMap::new(), None), &())
+    }
+
+    fn body(result: &RouteResult) -> String {
+        String::from_utf8(result.response.body.clone().unwrap_or_default()).unwrap()
+    }
+
+    fn allow(result: &RouteResult) -> String {
+        find_header(&result.response.header.headers, "Allow").map(|(_, v)| v.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn captures_path_params() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/nodes/{name}/state", reply("state"));
+
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/node1/state")), "state name=node1");
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/node1/state?x=1")), "state name=node1");
+        assert_eq!(route(&router, HttpVerb::Get, "/nodes/node1").response.header.status, HttpStatus::NotFound);
+        assert_eq!(route(&router, HttpVerb::Get, "/nodes/node1/state/extra").response.header.status, HttpStatus::NotFound);
+    }
+
+    #[test]
+    fn params_are_decoded_after_splitting() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/nodes/{name}", reply("node"));
+
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/living%20room")), "node name=living room");
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/a%2Fb")), "node name=a/b");
+    }
+
+    #[test]
+    fn parse_gives_none_for_values_that_do_not_parse() {
+        let params = PathParams { values: HashMap::from([("state".to_string(), "2".to_string()), ("name".to_string(), "x".to_string())]) };
+
+        assert_eq!(params.parse::<i32>("state"), Some(2));
+        assert_eq!(params.parse::<i32>("name"), None);
+        assert_eq!(params.parse::<i32>("missing"), None);
+    }
+
+    #[test]
+    fn first_registered_route_wins() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/nodes/all", reply("all"));
+        router.add(HttpVerb::Get, "/nodes/{name}", reply("node"));
+        router.add(HttpVerb::Get, "/nodes/other", reply("other"));
+
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/all")), "all");
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/nodes/other")), "node name=other");
+    }
+
+    #[test]
+    fn catch_all_takes_the_rest_of_the_path() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/ui/{*path}", reply("ui"));
+
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/ui/js/app.js")), "ui path=js/app.js");
+        assert_eq!(body(&route(&router, HttpVerb::Get, "/ui")), "ui path=");
+        assert_eq!(route(&router, HttpVerb::Get, "/uix").response.header.status, HttpStatus::NotFound);
+    }
+
+    #[test]
+    fn wrong_verb_lists_the_allowed_ones() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/nodes/{name}", reply("get"));
+        router.add(HttpVerb::Put, "/nodes/{name}", reply("put"));
+
+        let result = route(&router, HttpVerb::Delete, "/nodes/node1");
+        assert_eq!(result.response.header.status, HttpStatus::MethodNotAllowed);
+        assert_eq!(allow(&result), "GET, PUT, HEAD, OPTIONS");
+
+        let result = route(&router, HttpVerb::Options, "/nodes/node1");
+        assert_eq!(result.response.header.status, HttpStatus::NoContent);
+        assert_eq!(allow(&result), "GET, PUT, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn head_uses_the_get_handler_without_a_body() {
+        let mut router = Router::create();
+        router.add(HttpVerb::Get, "/nodes", reply("nodes"));
+
+        let result = route(&router, HttpVerb::Head, "/nodes");
+        assert_eq!(result.response.header.status, HttpStatus::Ok);
+        assert_eq!(result.response.header.content_length, "nodes".len());
+        assert!(result.response.body.is_none());
+    }
+
+    #[test]
+    fn permission_is_checked_on_the_named_node() {
+        let mut access = AccessPolicy::default();
+        access.roles.insert("viewer".to_string(), vec![Grant { permission: Permission::ReadState, nodes: vec!["node1".to_string()], tags: vec![] }]);
+        access.assignments.insert("alice".to_string(), vec!["viewer".to_string()]);
+
+        let mut router = Router::create();
+        router.set_access_policy(Arc::new(access));
+        router.add_with_permission(HttpVerb::Get, "/nodes/{name}", Permission::ReadState, reply("node"));
+
+        let request = |path: &str, principal: Option<&str>| {
+            let mut request = HttpRequest::create(path.to_string(), HttpVerb::Get, "text/plain".to_string(), HashMap::new(), None);
+            request.principal = principal.map(|name| Principal { name: name.to_string(), method: AuthMethod::ApiKey });
+            router.route(request, &()).response.header.status
+        };
+
+        assert_eq!(request("/nodes/node1", Some("alice")), HttpStatus::Ok);
+        assert_eq!(request("/nodes/node2", Some("alice")), HttpStatus::Forbidden);
+        assert_eq!(request("/nodes/node1", Some("bob")), HttpStatus::Forbidden);
+        assert_eq!(request("/nodes/node1", None), HttpStatus::Forbidden);
+    }
+}
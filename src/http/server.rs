@@ -1,244 +1,276 @@
 ﻿use std::collections::HashMap;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-use std::process::id;
-use std::sync::{Arc, mpsc, Mutex};
-use std::sync::mpsc::{channel, Sender};
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::Duration;
+use std::io::{BufRead, ErrorKind};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
-use crate::{Command, CommandType, Event, EventType, HttpClient, HttpResponse, Log, Logger, ResolverMessage};
-use crate::http::common::{HttpRequest, HttpResponseHeader, HttpStatus, HttpVerb};
-use crate::io::{UpdateNodeStateRequest, UpdateNodeStateResponse, GetNodeStateResponse};
+use crate::{Command, CommandType, Event, HttpClient, HttpResponse, Log, Logger, ResolverMessage};
+use crate::http::common::{DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_HEADER_COUNT, HttpRequest, HttpStatus, HttpVerb, MAX_HEADER_SIZE};
+use crate::io::{UpdateNodeStateRequest, GetNodeStateResponse, NodeStateRequest};
 
-use std::str::from_utf8;
 use crate::common::ChangeNodeStateCommand;
-use crate::io::network::NameRequest;
+use crate::common::permissions::{AccessPolicy, Permission};
+use crate::io::network::{NameRequest, NodeLinks};
+use crate::http::client::HttpConnectionPool;
+use crate::http::error::HttpError;
+use crate::http::router::{PathParams, RouteResult, Router};
+use crate::http::middleware::{LoggingMiddleware, MiddlewareChain, RateLimit, RateLimitMiddleware, RequestIdMiddleware};
+use crate::http::tls::{NetworkStream, TlsServerFiles};
+use crate::http::auth::{error_result, AuthConfig, AuthMiddleware};
+use crate::http::sse::{event_stream, EventFilter};
+use crate::events::hub::{EventHub, PublishedEvent};
+use crate::http::websocket::{handshake_response, is_upgrade_request, Message, WebSocket};
+use crate::common::{AuthMethod, Principal};
+use crate::io::{NodeListResponse, TokenResponse, WebSocketCommand, WebSocketReply};
+use crate::http::static_files::StaticFiles;
+use crate::http::compression::{CompressionMiddleware, CompressionSettings};
+use std::path::PathBuf;
+use crate::common::queue::QueueSender;
+use crate::http::reactor::{Reactor, RequestHandler, unavailable};
+use crate::http::access_log::{AccessLogMiddleware, AccessLogSettings};
+use crate::http::cors::{CorsMiddleware, CorsSettings};
+
+/// The page served at `/dashboard`.
+const DASHBOARD_PAGE: &str = include_str!("dashboard.html");
+
+/// The largest message accepted from a WebSocket client.
+const WEBSOCKET_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// How long a WebSocket waits for the client before checking for events to push.
+const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a WebSocket waits for the rest of a frame.
+const WEBSOCKET_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a WebSocket client can be silent before it is pinged.
+const WEBSOCKET_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a WebSocket client has to answer a ping before the connection is dropped.
+const WEBSOCKET_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a token from `/auth/token` is valid for, unless a shorter or longer time is asked for.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// The longest a token from `/auth/token` can be valid for.
+const MAX_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub(crate) struct HttpServer {
-    thread: JoinHandle<()>,
+    reactor: Reactor,
 }
 
-type Connection = Box<dyn FnOnce() + Send + 'static>;
-
-struct ConnectionPool {
-    handlers: Vec<ConnectionHandler>,
-    sender: Sender<Connection>,
-    logger: Logger,
-}
-
-struct ConnectionHandler {
-    id: usize,
-    thread: JoinHandle<()>,
+pub(crate) struct HttpServerSettings {
+    /// Threads running route handlers. Connections are waited on by a single thread however many there are.
+    pub pool_size: usize,
+    pub max_body_size: usize,
+    /// The largest request line and headers accepted, larger ones are answered with `431`.
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+    /// How long a client has to send the rest of a request once it has started, answered with `408` if it takes longer.
+    pub request_timeout: Duration,
+    /// How long a client may go without reading any of its response before the connection is dropped.
+    pub write_timeout: Duration,
+    /// How long an idle keep-alive connection is held open waiting for the next request.
+    pub keep_alive_timeout: Duration,
+    /// The number of requests served on one connection before it is closed.
+    pub max_keep_alive_requests: usize,
+    /// Per client request limit, `None` to allow any number of requests.
+    pub rate_limit: Option<RateLimit>,
+    /// Serve https with this certificate and key, plain http if `None`.
+    pub tls: Option<TlsServerFiles>,
+    /// The credentials requests must carry, every request is let through if `None`.
+    pub auth: Option<AuthConfig>,
+    /// A directory served under `/ui/`, such as a dashboard.
+    pub static_root: Option<PathBuf>,
+    /// Compress responses for clients that accept it, never if `None`.
+    pub compression: Option<CompressionSettings>,
+    /// Write an entry for each request, no access log if `None`.
+    pub access_log: Option<AccessLogSettings>,
+    /// Let browser pages on other origins call the api, none may if `None`.
+    pub cors: Option<CorsSettings>,
+    /// Open connections, further ones wait to be accepted until one closes.
+    /// The limit on open files must allow for this many.
+    pub max_connections: usize,
+    /// Event streams and WebSockets open at once, each holds a thread of its own and counts towards
+    /// `max_connections`. Further ones are answered with `503 Service Unavailable`.
+    pub max_streams: usize,
+    /// Requests waiting for a route handler, further ones are answered with `503 Service Unavailable`.
+    pub max_queued_requests: usize,
 }
 
-struct ConnectionContext {
-    id: Uuid,
-    slug: String,
-    from: String,
-    event_sender: Sender<Event>,
-    command_sender: Sender<Command>,
-    stream: TcpStream,
-    logger: Logger,
+/// Everything the routes need from the rest of the controller.
+pub(crate) struct RouteState {
     name_resolver: Sender<ResolverMessage>,
-}
-
-struct RouteResult {
-    response: HttpResponse,
-    events: Vec<Event>,
-    commands: Vec<Command>
-}
-
-type Route = Box<dyn FnOnce(HttpRequest) -> RouteResult + Send + 'static>;
-
-struct Routes {
-    routes: HashMap<(String, HttpVerb), Route>
+    client_pool: HttpConnectionPool,
+    events: EventHub,
+    /// Commands that do not come from a single request, such as those sent over a WebSocket.
+    command_sender: QueueSender<Command>,
+    static_files: Option<StaticFiles>,
+    /// Signs the tokens handed out by `/auth/token`.
+    auth: Option<AuthConfig>,
+    /// Limits the events streamed and commands taken to the nodes a principal has been granted.
+    access: Option<Arc<AccessPolicy>>,
 }
 
 impl HttpServer {
-    pub fn create(address: String, event_sender: Sender<Event>, command_sender: Sender<Command>, name_resolver: Sender<ResolverMessage>, log: &Log) -> Result<HttpServer, &'static str> {
+    pub fn create(address: String, settings: HttpServerSettings, event_sender: QueueSender<Event>, command_sender: QueueSender<Command>, nodes: NodeLinks, events: EventHub, log: &Log) -> Result<HttpServer, HttpError> {
         let logger = log.get_logger("http_server".to_string());
-        let connection_pool = ConnectionPool::new(4, log);
-        match TcpListener::bind(address) {
-            Ok(listener) => {
-                let thread = thread::spawn(move || loop {
-                    for stream in listener.incoming() {
-                        match stream {
-                            Ok(stream) => {
-                                let remote = stream.peer_addr().unwrap();
-                                logger.log_info(format!("Request received from {}", remote)).unwrap();
-                                let context = ConnectionContext::create(String::from(remote.ip().to_string()), event_sender.clone(), command_sender.clone(), stream, name_resolver.clone(), &logger);
-
-                                let es = event_sender.clone();
-                                let cs = command_sender.clone();
-                                connection_pool.handle_connect(|| { handle_connect(context) });
-                            }
-                            Err(_) => {
-                                logger.log_error("Unable to connection to stream".to_string()).unwrap();
-                            }
-                        }
-                    }
-                });
-
-                Ok(HttpServer {
-                    thread,
-                })
-            }
-            Err(_) => Err("Could not bind to address.")
+        let tls = match &settings.tls {
+            Some(files) => Some(files.load()?),
+            None => None
+        };
+        let settings = Arc::new(settings);
+        let mut router = routes(&settings);
+        let access = settings.auth.as_ref().and_then(|a| a.access.clone()).map(Arc::new);
+        if let Some(access) = &access {
+            router.set_access_policy(access.clone());
         }
-    }
-}
-
-impl ConnectionPool {
-    pub fn new(size: usize, log: &Log) -> ConnectionPool {
-        let logger = log.get_logger("connection_pool".to_string());
+        let middleware = middleware(&settings, log)?;
+        let route_state = RouteState { name_resolver: nodes.resolver, client_pool: nodes.pool, events, command_sender: command_sender.clone(), static_files: settings.static_root.clone().map(StaticFiles::create), auth: settings.auth.clone(), access };
 
-        let (sender, receiver) = mpsc::channel();
+        let handler: RequestHandler = Arc::new(move |request, logger| {
+            // Work is queued before responding, so a full queue can still be reported to the client.
+            middleware.handle(request, |r| queue_work(router.route(r, &route_state), &event_sender, &command_sender, logger))
+        });
 
-        let mut handlers = Vec::with_capacity(size);
+        let listener = TcpListener::bind(address)?;
+        logger.log_info(format!("Listening on {}", listener.local_addr()?)).unwrap();
 
-        let receiver = Arc::new(Mutex::new(receiver));
+        Ok(HttpServer {
+            reactor: Reactor::start(listener, settings, tls, handler, log)?,
+        })
+    }
 
-        for id in 0..size {
-            let name = format!("worker_{}", id);
+    /// Stop accepting connections and wait for the ones open to finish their current request.
+    /// Event streams and WebSockets only end once the `EventHub` is closed.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.reactor.shutdown(deadline)
+    }
+}
 
-            let handler_logger = log.get_logger(name);
-            handler_logger.log_info("Starting".to_string()).unwrap();
+impl Default for HttpServerSettings {
+    fn default() -> Self {
+        HttpServerSettings {
+            pool_size: 4,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_header_size: MAX_HEADER_SIZE,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            request_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_keep_alive_requests: 100,
+            rate_limit: None,
+            tls: None,
+            auth: None,
+            static_root: None,
+            compression: Some(CompressionSettings::default()),
+            access_log: None,
+            cors: None,
+            max_connections: 4096,
+            max_streams: 128,
+            max_queued_requests: 64,
+        }
+    }
+}
 
-            handlers.push(ConnectionHandler::new(id, receiver.clone(), handler_logger));
-        };
+/// Raise the events and queue the commands of a route result.
+/// If a queue refuses them the client is told to try again later instead.
+fn queue_work(mut result: RouteResult, event_sender: &QueueSender<Event>, command_sender: &QueueSender<Command>, logger: &Logger) -> RouteResult {
+    for event in result.events.drain(..) {
+        logger.log_info(format!("Rising event - id: {}", event.id)).unwrap();
 
-        ConnectionPool {
-            handlers,
-            sender,
-            logger,
+        if let Err(e) = event_sender.send(event) {
+            logger.log_warning(format!("Event refused - {}", e)).unwrap();
+            return RouteResult::create(unavailable());
         }
     }
 
-    fn handle_connect<F>(&self, f: F) where
-        F: FnOnce() + Send + 'static,
-    {
-        let connection = Box::new(f);
+    for command in result.commands.drain(..) {
+        logger.log_info(format!("Queuing command - id: {}", command.id)).unwrap();
 
-        self.sender.send(connection).unwrap();
+        if let Err(e) = command_sender.send(command) {
+            logger.log_warning(format!("Command refused - {}", e)).unwrap();
+            return RouteResult::create(unavailable());
+        }
     }
+
+    result
 }
 
-impl ConnectionHandler {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Connection>>>, logger: Logger) -> ConnectionHandler {
-        let thread = thread::spawn(move || loop {
-            let connection = receiver.lock().unwrap().recv().unwrap();
-            logger.log_info("Connection receiver".to_string()).unwrap();
-            connection();
-        });
+fn middleware(settings: &HttpServerSettings, log: &Log) -> Result<MiddlewareChain, HttpError> {
+    let mut chain = MiddlewareChain::create();
 
-        ConnectionHandler { id, thread }
+    // First, so its entry is written last and records the response as it is sent.
+    if let Some(access_log) = &settings.access_log {
+        chain.add(AccessLogMiddleware::create(access_log, log.get_logger("access".to_string()))?);
     }
-}
 
-impl ConnectionContext {
-    fn create(from: String, event_sender: Sender<Event>, command_sender: Sender<Command>, stream: TcpStream, name_resolver: Sender<ResolverMessage>, logger: &Logger) -> ConnectionContext {
-        let id = Uuid::new_v4();
-        let slug = String::from(Uuid::new_v4().to_string().split_at(6).0);
-        let connection_logger = logger.create_from(slug.clone());
-
-        ConnectionContext {
-            id,
-            slug,
-            from,
-            event_sender,
-            command_sender,
-            stream,
-            name_resolver,
-            logger: connection_logger,
-        }
+    chain.add(LoggingMiddleware::create(log.get_logger("requests".to_string())));
+
+    // Early in the chain so it compresses whatever the rest of it answers.
+    if let Some(compression) = &settings.compression {
+        chain.add(CompressionMiddleware::create(compression.clone()));
     }
 
-    fn get_request(&self) -> Result<HttpRequest, &'static str> {
-        HttpRequest::from_stream(&self.stream, &self.logger)
+    chain.add(RequestIdMiddleware);
+
+    // Before the rate limit and auth, preflight requests carry no credentials and are answered here.
+    if let Some(cors) = &settings.cors {
+        chain.add(CorsMiddleware::create(cors.clone()));
     }
 
-    fn send_response(&mut self, mut response: HttpResponse) -> Result<(), &'static str> {
-        match self.stream.write(&response.to_bytes()) {
-            Ok(_) => {
-                match self.stream.flush() {
-                    Ok(_) => { Ok(()) }
-                    Err(_) => {
-                        Err("Flush failed.")
-                    }
-                }
-            }
-            Err(_) => {
-                Err("Write failed.")
-            }
-        }
+    if let Some(limit) = settings.rate_limit {
+        chain.add(RateLimitMiddleware::create(limit));
     }
-}
 
-fn handle_connect(mut context: ConnectionContext) {
-    match context.get_request() {
-        Ok(request) => {
-            context.logger.log_info(format!("Request for route {}. Type: {}", request.header.route, request.header.verb.get_str())).unwrap();
+    if let Some(auth) = &settings.auth {
+        let mut auth = AuthMiddleware::create(auth.clone());
 
-            let result = router(request, context.name_resolver.clone());
-            match context.send_response(result.response) {
-                Ok(_) => {
-                    context.logger.log_success("Response sent.".to_string()).unwrap();
-                }
-                Err(message) => {
-                    context.logger.log_error(format!("Error sending response - {}", message)).unwrap();
-                }
-            }
-            
-            for event in result.events {
-                context.logger.log_info(format!("Rising event - id: {}", event.id)).unwrap();
-                context.event_sender.send(event).unwrap();
-            }
-            
-            for command in result.commands {
-                context.logger.log_info(format!("Queuing command - id: {}", command.id)).unwrap();
-                context.command_sender.send(command).unwrap();
-            }
-        }
-        Err(message) => {
-            context.logger.log_error(format!("Could not get request. Error - {}", message)).unwrap();
-        }
+        // The pages themselves hold nothing, they ask for a key before calling the api.
+        // Browsers fetch their scripts and styles without one.
+        auth.allow_anonymous("/dashboard");
+        auth.allow_anonymous_under("/ui");
+
+        chain.add(auth);
     }
+
+    Ok(chain)
 }
 
-/*
-impl Routes {
-    pub fn create() -> Routes {
-        let mut map = HashMap::new();
-        
-        map.insert(("/set-state".to_string(), HttpVerb::GET), Box::new(set_state_route));
-        
-        Routes {
-            routes: map
-        }
+fn routes(settings: &HttpServerSettings) -> Router<RouteState> {
+    let mut router = Router::create();
+
+    // The node for `/node/set-state` is in the body, so its grant is checked again by the orchestrator.
+    router.add_with_permission(HttpVerb::Post, "/node/set-state", Permission::ChangeState, set_state_route);
+    router.add_with_permission(HttpVerb::Get, "/node/get-state/{name}", Permission::ReadState, get_state_route);
+    router.add_with_permission(HttpVerb::Get, "/nodes/{name}/state", Permission::ReadState, get_state_route);
+    router.add_with_permission(HttpVerb::Put, "/nodes/{name}/state", Permission::ChangeState, put_state_route);
+    router.add_with_permission(HttpVerb::Put, "/nodes/{name}/state/{state}", Permission::ChangeState, put_state_param_route);
+    router.add_with_permission(HttpVerb::Get, "/events/stream", Permission::ReadState, event_stream_route);
+    router.add_with_permission(HttpVerb::Get, "/ws", Permission::ReadState, websocket_route);
+    router.add_with_permission(HttpVerb::Get, "/nodes", Permission::ReadState, list_nodes_route);
+    router.add(HttpVerb::Get, "/dashboard", dashboard_route);
+
+    if settings.auth.is_some() {
+        router.add(HttpVerb::Post, "/auth/token", token_route);
     }
-}
-*/
 
-fn router(request: HttpRequest, name_resolver: Sender<ResolverMessage>) -> RouteResult {
-    //let client = HttpClient::create("192.168.0.226:80".to_string());
-    
-    match (request.header.route.as_str(), request.header.verb) {
-        ("/node/set-state", HttpVerb::POST) => set_state_route(request),
-        (r, HttpVerb::GET) if r.starts_with("/node/get-state") => get_state_route(request, name_resolver),
-        (_, _) => not_found_route(request)
+    if settings.static_root.is_some() {
+        router.add(HttpVerb::Get, "/ui/{*path}", static_files_route);
     }
+
+    router
 }
 
-fn set_state_route(request: HttpRequest) -> RouteResult {
+fn set_state_route(request: HttpRequest, _params: &PathParams, _state: &RouteState) -> RouteResult {
     match request.body {
         None => {
             let body = Some("Missing request body.".as_bytes().to_vec());
             RouteResult {
                 response: HttpResponse::create(HttpStatus::BadRequest, "text/plain".to_string(), HashMap::new(), body),
                 events: vec![],
-                commands: vec![]
+                commands: vec![],
+                upgrade: None
             }
         }
         Some(request_body) => {
@@ -246,9 +278,10 @@ fn set_state_route(request: HttpRequest) -> RouteResult {
                 Ok(parsed_request) => {
                     let body = Some("Update state action queued.".as_bytes().to_vec());
                     RouteResult {
-                        response: HttpResponse::create(HttpStatus::Ok, "text/plain".to_string(), HashMap::new(), body),
+                        response: HttpResponse::create(HttpStatus::Accepted, "text/plain".to_string(), HashMap::new(), body),
                         events: vec![],
-                        commands: vec![ Command { id: Uuid::new_v4(), command_type: CommandType::ChangeNodeState(ChangeNodeStateCommand { node: parsed_request.node, new_state: parsed_request.new_state }) } ]
+                        commands: vec![ Command { id: Uuid::new_v4(), command_type: CommandType::ChangeNodeState(ChangeNodeStateCommand { node: parsed_request.node, new_state: parsed_request.new_state }), principal: request.principal.clone() } ],
+                        upgrade: None
                     }
                 }
                 Err(_) => {
@@ -256,7 +289,8 @@ fn set_state_route(request: HttpRequest) -> RouteResult {
                     RouteResult {
                         response: HttpResponse::create(HttpStatus::BadRequest, "text/plain".to_string(), HashMap::new(), body),
                         events: vec![],
-                        commands: vec![]
+                        commands: vec![],
+                        upgrade: None
                     }
                 }
             }
@@ -264,13 +298,327 @@ fn set_state_route(request: HttpRequest) -> RouteResult {
     }
 }
 
-fn get_state_route(request: HttpRequest, name_resolver: Sender<ResolverMessage>) -> RouteResult {
-    let name = request.header.route.split('/').last().unwrap().to_string();
+fn put_state_route(request: HttpRequest, params: &PathParams, _state: &RouteState) -> RouteResult {
+    let parsed = request.body.map(NodeStateRequest::from_bytes);
+
+    match (params.get("name"), parsed) {
+        (Some(name), Some(Ok(parsed_request))) => {
+            let body = Some("Update state action queued.".as_bytes().to_vec());
+            RouteResult {
+                response: HttpResponse::create(HttpStatus::Accepted, "text/plain".to_string(), HashMap::new(), body),
+                events: vec![],
+                commands: vec![ Command { id: Uuid::new_v4(), command_type: CommandType::ChangeNodeState(ChangeNodeStateCommand { node: name.to_string(), new_state: parsed_request.new_state }), principal: request.principal.clone() } ],
+                upgrade: None
+            }
+        }
+        _ => {
+            let body = Some("Invalid request.".as_bytes().to_vec());
+            RouteResult {
+                response: HttpResponse::create(HttpStatus::BadRequest, "text/plain".to_string(), HashMap::new(), body),
+                events: vec![],
+                commands: vec![],
+                upgrade: None
+            }
+        }
+    }
+}
+
+fn put_state_param_route(request: HttpRequest, params: &PathParams, _state: &RouteState) -> RouteResult {
+    match (params.get("name"), params.parse::<u8>("state")) {
+        (Some(name), Some(new_state)) => {
+            let body = Some("Update state action queued.".as_bytes().to_vec());
+            RouteResult {
+                response: HttpResponse::create(HttpStatus::Accepted, "text/plain".to_string(), HashMap::new(), body),
+                events: vec![],
+                commands: vec![ Command { id: Uuid::new_v4(), command_type: CommandType::ChangeNodeState(ChangeNodeStateCommand { node: name.to_string(), new_state }), principal: request.principal.clone() } ],
+                upgrade: None
+            }
+        }
+        _ => {
+            let body = Some("Invalid state.".as_bytes().to_vec());
+            RouteResult {
+                response: HttpResponse::create(HttpStatus::BadRequest, "text/plain".to_string(), HashMap::new(), body),
+                events: vec![],
+                commands: vec![],
+                upgrade: None
+            }
+        }
+    }
+}
+
+/// Stream events as they happen, filtered by the `type` and `node` query parameters.
+/// A client that reconnects with `Last-Event-ID` is first sent the events it missed, if they are still in the history.
+fn event_stream_route(request: HttpRequest, _params: &PathParams, state: &RouteState) -> RouteResult {
+    let filter = EventFilter::from_url(&request.header.url).restrict(state.access.clone(), request.principal.clone());
+    let last_event_id = request.header.headers.get("LAST-EVENT-ID").and_then(|id| id.trim().parse::<u64>().ok());
+
+    let mut headers = HashMap::new();
+    headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+
+    let stream = event_stream(&state.events, filter, last_event_id);
+
+    RouteResult::create(HttpResponse::create_chunked(HttpStatus::Ok, "text/event-stream".to_string(), headers, stream))
+}
+
+fn static_files_route(request: HttpRequest, params: &PathParams, state: &RouteState) -> RouteResult {
+    let path = params.get("path").unwrap_or("");
+
+    // Relative links in the index page only resolve under `/ui/`.
+    if path.is_empty() && !request.header.url.path.ends_with('/') {
+        let mut headers = HashMap::new();
+        headers.insert("Location".to_string(), "/ui/".to_string());
+
+        return RouteResult::create(HttpResponse::create(HttpStatus::MovedPermanently, "text/plain".to_string(), headers, None));
+    }
+
+    match &state.static_files {
+        Some(files) => RouteResult::create(files.serve(&request, path)),
+        None => RouteResult::create(HttpResponse::create(HttpStatus::NotFound, "text/plain".to_string(), HashMap::new(), None))
+    }
+}
+
+fn list_nodes_route(_request: HttpRequest, _params: &PathParams, state: &RouteState) -> RouteResult {
+    let (rc, rx) = channel();
+
+    let nodes = match state.name_resolver.send(ResolverMessage::ListNames(rc)).ok().and_then(|_| rx.recv().ok()) {
+        Some(nodes) => nodes,
+        None => return resolver_stopped()
+    };
+
+    let response = NodeListResponse { nodes };
+
+    let response = match response.to_bytes() {
+        Ok(body) => HttpResponse::create(HttpStatus::Ok, "application/json".to_string(), HashMap::new(), Some(body)),
+        Err(_) => {
+            let body = Some("Could not serialize result.".as_bytes().to_vec());
+            HttpResponse::create(HttpStatus::InternalError, "text/plain".to_string(), HashMap::new(), body)
+        }
+    };
+
+    RouteResult::create(response)
+}
+
+/// Sign a bearer token for the principal, valid for `validFor` seconds if given.
+/// Only a principal that authenticated with an api key can have one, a token cannot be used to get
+/// another or it could be renewed forever after its key is removed.
+fn token_route(request: HttpRequest, _params: &PathParams, state: &RouteState) -> RouteResult {
+    let (auth, principal) = match (&state.auth, &request.principal) {
+        (Some(auth), Some(principal)) => (auth, principal),
+        _ => return error_result(HttpStatus::Unauthorized, "unauthorized", "Missing credentials.", HashMap::new())
+    };
+
+    if principal.method != AuthMethod::ApiKey {
+        return error_result(HttpStatus::Forbidden, "forbidden", "Tokens are only issued for an api key.", HashMap::new());
+    }
+
+    let valid_for = match request.header.url.query_value("validFor").map(|v| v.parse::<u64>()) {
+        None => TOKEN_LIFETIME,
+        Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds).min(MAX_TOKEN_LIFETIME),
+        Some(_) => return error_result(HttpStatus::BadRequest, "bad_request", "validFor must be a number of seconds.", HashMap::new())
+    };
+
+    let token = match auth.issue_token(principal.name.clone(), valid_for) {
+        Some(token) => token,
+        None => return error_result(HttpStatus::NotImplemented, "not_implemented", "Tokens are not enabled, there is no token secret.", HashMap::new())
+    };
+
+    let response = TokenResponse { token, token_type: "Bearer", expires_in: valid_for.as_secs() };
+
+    let mut headers = HashMap::new();
+    headers.insert("Cache-Control".to_string(), "no-store".to_string());
+
+    match serde_json::to_vec(&response) {
+        Ok(body) => RouteResult::create(HttpResponse::create(HttpStatus::Ok, "application/json".to_string(), headers, Some(body))),
+        Err(_) => {
+            let body = Some("Could not serialize result.".as_bytes().to_vec());
+            RouteResult::create(HttpResponse::create(HttpStatus::InternalError, "text/plain".to_string(), HashMap::new(), body))
+        }
+    }
+}
+
+/// The operator dashboard, compiled into the binary so it is always there.
+fn dashboard_route(_request: HttpRequest, _params: &PathParams, _state: &RouteState) -> RouteResult {
+    let mut headers = HashMap::new();
+    headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+
+    let body = Some(DASHBOARD_PAGE.as_bytes().to_vec());
+    RouteResult::create(HttpResponse::create(HttpStatus::Ok, "text/html; charset=utf-8".to_string(), headers, body))
+}
+
+/// Upgrade to a WebSocket that pushes events, filtered like `/events/stream`, and accepts commands.
+fn websocket_route(request: HttpRequest, _params: &PathParams, state: &RouteState) -> RouteResult {
+    if !is_upgrade_request(&request) {
+        let mut headers = HashMap::new();
+        headers.insert("Upgrade".to_string(), "websocket".to_string());
+
+        let body = Some("Expected a WebSocket upgrade request.".as_bytes().to_vec());
+        return RouteResult::create(HttpResponse::create(HttpStatus::UpgradeRequired, "text/plain".to_string(), headers, body));
+    }
+
+    let filter = EventFilter::from_url(&request.header.url).restrict(state.access.clone(), request.principal.clone());
+    let (_, events) = state.events.subscribe(None);
+    let command_sender = state.command_sender.clone();
+    let principal = request.principal.clone();
+    let access = state.access.clone();
+
+    let mut result = RouteResult::create(handshake_response(&request));
+
+    result.upgrade = Some(Box::new(move |stream, logger| {
+        let socket = WebSocket::create(stream, WEBSOCKET_MAX_MESSAGE_SIZE);
+        websocket_session(socket, events, filter, command_sender, principal, access, logger);
+    }));
+
+    result
+}
+
+fn websocket_session(mut socket: WebSocket<NetworkStream>, events: Receiver<Arc<PublishedEvent>>, filter: EventFilter, command_sender: QueueSender<Command>, principal: Option<Principal>, access: Option<Arc<AccessPolicy>>, logger: Logger) {
+    logger.log_info("WebSocket connected.".to_string()).unwrap();
+
+    let mut last_heard = Instant::now();
+    let mut pings_sent: u32 = 0;
+    // The payload of the ping waiting for a pong, and when it was sent.
+    let mut ping: Option<([u8; 4], Instant)> = None;
+
+    'session: loop {
+        loop {
+            match events.try_recv() {
+                Ok(event) if filter.matches(&event) => {
+                    if socket.send_text(&event.data).is_err() {
+                        break 'session;
+                    }
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(1001);
+                    break 'session;
+                }
+            }
+        }
+
+        match wait_for_frame(&mut socket) {
+            Ok(true) => last_heard = Instant::now(),
+            Ok(false) => {
+                match ping {
+                    Some((_, sent)) if sent.elapsed() >= WEBSOCKET_PONG_TIMEOUT => {
+                        logger.log_warning("WebSocket client did not answer a ping, closing.".to_string()).unwrap();
+                        let _ = socket.close(1001);
+                        break;
+                    }
+                    None if last_heard.elapsed() >= WEBSOCKET_PING_INTERVAL => {
+                        pings_sent = pings_sent.wrapping_add(1);
+                        let payload = pings_sent.to_be_bytes();
+
+                        if socket.send_ping(payload.to_vec()).is_err() {
+                            break;
+                        }
+
+                        ping = Some((payload, Instant::now()));
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+            Err(_) => break
+        }
+
+        match socket.read_message() {
+            Ok(Message::Text(text)) => {
+                let reply = match text.parse::<WebSocketCommand>() {
+                    Ok(WebSocketCommand::ChangeNodeState(change)) if !may_change(access.as_deref(), principal.as_ref(), &change.node) => {
+                        logger.log_warning(format!("Command for {} refused, permission denied.", change.node)).unwrap();
+                        WebSocketReply::Error { message: "Permission denied.".to_string() }
+                    }
+                    Ok(WebSocketCommand::ChangeNodeState(change)) => {
+                        let command = Command { id: Uuid::new_v4(), command_type: CommandType::ChangeNodeState(ChangeNodeStateCommand { node: change.node, new_state: change.new_state }), principal: principal.clone() };
+
+                        logger.log_info(format!("Queuing command - id: {}", command.id)).unwrap();
+                        let id = command.id.to_string();
+
+                        match command_sender.send(command) {
+                            Ok(_) => WebSocketReply::Queued { id },
+                            Err(e) => {
+                                logger.log_warning(format!("Command {} refused - {}", id, e)).unwrap();
+                                WebSocketReply::Error { message: "Too busy, try again later.".to_string() }
+                            }
+                        }
+                    }
+                    Err(_) => WebSocketReply::Error { message: "Invalid command.".to_string() }
+                };
+
+                if socket.send_text(&reply.to_string().unwrap()).is_err() {
+                    break;
+                }
+            }
+            Ok(Message::Binary(_)) => {
+                let reply = WebSocketReply::Error { message: "Commands must be sent as text.".to_string() };
+
+                if socket.send_text(&reply.to_string().unwrap()).is_err() {
+                    break;
+                }
+            }
+            // Unsolicited pongs are allowed, only the answer to the last ping counts.
+            Ok(Message::Pong(payload)) => {
+                if ping.is_some_and(|(sent, _)| payload == sent) {
+                    ping = None;
+                }
+            }
+            Ok(Message::Close(code)) => {
+                logger.log_debug(format!("WebSocket closed by the client, code {:?}.", code)).unwrap();
+                break;
+            }
+            Err(e) => {
+                logger.log_warning(format!("WebSocket error - {}", e)).unwrap();
+                let _ = socket.close(1002);
+                break;
+            }
+        }
+    }
+
+    socket.get_mut().get_mut().close();
+    logger.log_info("WebSocket closed.".to_string()).unwrap();
+}
+
+/// Check a WebSocket client may change the state of `node`, before its command is queued.
+fn may_change(access: Option<&AccessPolicy>, principal: Option<&Principal>, node: &str) -> bool {
+    match (access, principal) {
+        (Some(access), Some(principal)) => access.allows(principal, Permission::ChangeState, Some(node)),
+        _ => true
+    }
+}
+
+/// Wait a short while for the client to send something, so events can be pushed in between.
+/// Returns false if nothing arrived and an error if the connection is gone.
+fn wait_for_frame(socket: &mut WebSocket<NetworkStream>) -> Result<bool, HttpError> {
+    let stream = socket.get_mut();
+
+    stream.get_ref().tcp().set_read_timeout(Some(WEBSOCKET_POLL_INTERVAL))?;
+
+    let available = match stream.fill_buf() {
+        Ok([]) => return Err(HttpError::ConnectionClosed),
+        Ok(_) => true,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => false,
+        Err(e) => return Err(HttpError::Io(e))
+    };
+
+    // Give the rest of a frame that has started arriving time to come in.
+    stream.get_ref().tcp().set_read_timeout(Some(WEBSOCKET_READ_TIMEOUT))?;
+
+    Ok(available)
+}
+
+fn get_state_route(_request: HttpRequest, params: &PathParams, state: &RouteState) -> RouteResult {
+    let name = params.get("name").unwrap_or("").to_string();
     
     let (rc, rx) = channel();
-    name_resolver.send(ResolverMessage::GetAddress(NameRequest { name, reply_channel: rc })).unwrap();
 
-    match rx.recv().unwrap() {
+    let address = match state.name_resolver.send(ResolverMessage::GetAddress(NameRequest { name, reply_channel: rc })).ok().and_then(|_| rx.recv().ok()) {
+        Some(address) => address,
+        None => return resolver_stopped()
+    };
+
+    match address {
         None => {
 
             let body = Some("Node not found.".as_bytes().to_vec());
@@ -278,11 +626,12 @@ fn get_state_route(request: HttpRequest, name_resolver: Sender<ResolverMessage>)
             RouteResult {
                 response: HttpResponse::create(HttpStatus::NotFound, "text/plain".to_string(), HashMap::new(), body),
                 events: vec![],
-                commands: vec![]
+                commands: vec![],
+                upgrade: None
             }
         }
         Some(addr) => {
-            let client = HttpClient::create(addr);
+            let client = HttpClient::with_pool(addr, state.client_pool.clone());
 
             let response =
                 match get_state(client) {
@@ -300,29 +649,25 @@ fn get_state_route(request: HttpRequest, name_resolver: Sender<ResolverMessage>)
                             };
                         response
                     }
-                    Err(e) => {
+                    Err(HttpError::Timeout) => {
+                        let body = Some("Node did not respond in time.".as_bytes().to_vec());
+                        HttpResponse::create(HttpStatus::GatewayTimeout, "text/plain".to_string(), HashMap::new(), body)
+                    }
+                    Err(_) => {
                         let body = Some("Could not get state.".as_bytes().to_vec());
-                        HttpResponse::create(HttpStatus::InternalError, "text/plain".to_string(), HashMap::new(), body)
+                        HttpResponse::create(HttpStatus::BadGateway, "text/plain".to_string(), HashMap::new(), body)
                     }
                 };
 
             RouteResult {
                 response,
                 events: vec![],
-                commands: vec![]
+                commands: vec![],
+                upgrade: None
             }
         }
     }
 }
- 
-fn not_found_route(request: HttpRequest) -> RouteResult {
-    let body = Some("Not found".as_bytes().to_vec());
-    RouteResult {
-        response: HttpResponse::create(HttpStatus::NotFound, "text/plain".to_string(), HashMap::new(), body),
-        events: vec![],
-        commands: vec![]
-    }
-}
 
 /*
 fn set_state(mut client: HttpClient, request: UpdateNodeStateRequest) -> Result<UpdateNodeStateResponse, &'static str> {
@@ -341,16 +686,22 @@ fn set_state(mut client: HttpClient, request: UpdateNodeStateRequest) -> Result<
 }
 */
 
-fn get_state(mut client: HttpClient) -> Result<GetNodeStateResponse, &'static str> {
+/// The name resolver has stopped, so no node can be found.
+fn resolver_stopped() -> RouteResult {
+    let body = Some("Node names could not be resolved.".as_bytes().to_vec());
+    RouteResult::create(HttpResponse::create(HttpStatus::InternalError, "text/plain".to_string(), HashMap::new(), body))
+}
+
+fn get_state(mut client: HttpClient) -> Result<GetNodeStateResponse, HttpError> {
     let response = client.get("/get-state".to_string(), "text/plain".to_string(), HashMap::new())?;
     match response.body {
         None => {
-            Err("No response body returned")
+            Err(HttpError::InvalidResponse("No response body returned"))
         }
         Some(body) => {
             match GetNodeStateResponse::from_bytes(body) {
                 Ok(response) => Ok(response),
-                Err(_) => Err("Unable to parse response")
+                Err(_) => Err(HttpError::InvalidResponse("Unable to parse response"))
             }
         }
     }
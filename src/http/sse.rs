@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+use crate::common::Principal;
+use crate::common::permissions::{AccessPolicy, Permission};
+use crate::events::hub::{EventHub, PublishedEvent};
+use crate::http::common::BodyStream;
+use crate::http::url::Url;
+
+/// How often a comment is sent on an idle stream, so proxies keep it open and a closed client is noticed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Which events a stream sends, an empty list lets everything through.
+pub(crate) struct EventFilter {
+    pub types: Vec<String>,
+    pub nodes: Vec<String>,
+    /// Events for a node are only sent if the principal may read its state, all are if `None`.
+    pub access: Option<ReadAccess>,
+}
+
+/// The principal a stream is for, and the policy that says which nodes it may read.
+pub(crate) struct ReadAccess {
+    policy: Arc<AccessPolicy>,
+    principal: Principal,
+}
+
+struct EventStream {
+    missed: std::vec::IntoIter<Arc<PublishedEvent>>,
+    receiver: Receiver<Arc<PublishedEvent>>,
+    filter: EventFilter,
+}
+
+impl EventFilter {
+    /// Read the filter from the `type` and `node` query parameters, each may be given more than once.
+    pub fn from_url(url: &Url) -> EventFilter {
+        EventFilter {
+            types: url.query_values("type").to_vec(),
+            nodes: url.query_values("node").to_vec(),
+            access: None,
+        }
+    }
+
+    /// Only let through events for nodes `principal` may read. Nothing is restricted without both.
+    pub fn restrict(mut self, policy: Option<Arc<AccessPolicy>>, principal: Option<Principal>) -> EventFilter {
+        if let (Some(policy), Some(principal)) = (policy, principal) {
+            self.access = Some(ReadAccess { policy, principal });
+        }
+
+        self
+    }
+
+    pub fn matches(&self, event: &PublishedEvent) -> bool {
+        let type_matches = self.types.is_empty() || self.types.iter().any(|t| t == event.event_type);
+
+        let node_matches = self.nodes.is_empty() || match &event.node {
+            Some(node) => self.nodes.contains(node),
+            None => false
+        };
+
+        // Events not about a node, such as the result of a test, are seen by anyone who may read any node.
+        let readable = match (&self.access, &event.node) {
+            (Some(access), Some(node)) => access.policy.allows(&access.principal, Permission::ReadState, Some(node)),
+            _ => true
+        };
+
+        type_matches && node_matches && readable
+    }
+}
+
+/// A `text/event-stream` body of the hub's events, starting with any after `last_event_id`.
+/// The stream only ends when the hub goes away or drops the subscriber for falling behind,
+/// or when writing fails because the client disconnected.
+pub(crate) fn event_stream(hub: &EventHub, filter: EventFilter, last_event_id: Option<u64>) -> BodyStream {
+    let (missed, receiver) = hub.subscribe(last_event_id);
+
+    Box::new(EventStream {
+        missed: missed.into_iter(),
+        receiver,
+        filter,
+    })
+}
+
+impl Iterator for EventStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        for event in self.missed.by_ref() {
+            if self.filter.matches(&event) {
+                return Some(format_event(&event));
+            }
+        }
+
+        loop {
+            match self.receiver.recv_timeout(KEEP_ALIVE_INTERVAL) {
+                Ok(event) if self.filter.matches(&event) => return Some(format_event(&event)),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Some(b": keep-alive\n\n".to_vec()),
+                Err(RecvTimeoutError::Disconnected) => return None
+            }
+        }
+    }
+}
+
+fn format_event(event: &PublishedEvent) -> Vec<u8> {
+    format!("id: {}\nevent: {}\ndata: {}\n\n", event.sequence, event.event_type, event.data).into_bytes()
+}
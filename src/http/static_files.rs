@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::http::common::{HttpRequest, HttpResponse, HttpStatus};
+
+/// The format of `Last-Modified` and `If-Modified-Since`.
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Serves the files in a directory. A directory is answered with its `index.html`.
+pub(crate) struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn create(root: PathBuf) -> StaticFiles {
+        StaticFiles { root }
+    }
+
+    /// Answer a request for `path`, relative to the root.
+    /// Conditional requests are answered with `304 Not Modified` if the file has not changed.
+    pub fn serve(&self, request: &HttpRequest, path: &str) -> HttpResponse {
+        let file = match self.resolve(path) {
+            Some(file) => file,
+            None => return not_found()
+        };
+
+        let (metadata, contents) = match (fs::metadata(&file), fs::read(&file)) {
+            (Ok(metadata), Ok(contents)) => (metadata, contents),
+            _ => return not_found()
+        };
+
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let etag = entity_tag(metadata.len(), modified);
+        let last_modified = DateTime::<Utc>::from(modified).format(HTTP_DATE_FORMAT).to_string();
+
+        let mut headers = HashMap::new();
+        headers.insert("ETag".to_string(), etag.clone());
+        headers.insert("Last-Modified".to_string(), last_modified);
+        headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+
+        if not_modified(request, &etag, modified) {
+            let mut response = HttpResponse::create(HttpStatus::NotModified, content_type(&file).to_string(), headers, None);
+            response.header.headers.remove("Content-Length");
+            return response;
+        }
+
+        HttpResponse::create(HttpStatus::Ok, content_type(&file).to_string(), headers, Some(contents))
+    }
+
+    /// Find the file for a request path, refusing anything that would leave the root.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            // Only plain names, no `..`, drive prefixes or separators hidden inside a decoded segment.
+            let mut components = Path::new(segment).components();
+
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if !segment.contains('\\') => file.push(name),
+                _ => return None
+            }
+        }
+
+        if file.is_dir() {
+            file.push("index.html");
+        }
+
+        // Symbolic links could still point outside the root.
+        let root = self.root.canonicalize().ok()?;
+        let file = file.canonicalize().ok()?;
+
+        match file.starts_with(&root) && file.is_file() {
+            true => Some(file),
+            false => None
+        }
+    }
+}
+
+fn not_found() -> HttpResponse {
+    let body = Some("Not found".as_bytes().to_vec());
+    HttpResponse::create(HttpStatus::NotFound, "text/plain".to_string(), HashMap::new(), body)
+}
+
+/// An `ETag` from the size and modification time of a file, which change whenever it is rewritten.
+fn entity_tag(length: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+
+    format!("\"{:x}-{:x}\"", length, modified)
+}
+
+fn not_modified(request: &HttpRequest, etag: &str, modified: SystemTime) -> bool {
+    // `If-None-Match` takes precedence when both are sent.
+    if let Some(tags) = request.header.headers.get("IF-NONE-MATCH") {
+        return tags.split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+
+    match request.header.headers.get("IF-MODIFIED-SINCE").and_then(|v| NaiveDateTime::parse_from_str(v.trim(), HTTP_DATE_FORMAT).ok()) {
+        Some(since) => {
+            let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+            modified <= since.timestamp()
+        }
+        None => false
+    }
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+    use crate::http::common::HttpRequestHeader;
+    use crate::http::url::Url;
+    use super::*;
+
+    /// A directory of files to serve, with a secret next to the root that must not be served.
+    /// Removed when dropped.
+    struct Site {
+        base: PathBuf,
+        files: StaticFiles,
+    }
+
+    impl Site {
+        fn create() -> Site {
+            let base = std::env::temp_dir().join(format!("piot-static-{}", Uuid::new_v4()));
+            let root = base.join("www");
+
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+            fs::write(root.join("app.js"), "let a = 1;").unwrap();
+            fs::write(root.join("sub").join("page.txt"), "page").unwrap();
+            fs::write(base.join("secret.txt"), "secret").unwrap();
+
+            Site { files: StaticFiles::create(root), base }
+        }
+
+        /// Resolve a path as it is sent under `/ui/`, decoded the same way the router does.
+        fn resolve(&self, raw: &str) -> Option<PathBuf> {
+            let url = Url::parse(&format!("/ui/{}", raw));
+            self.files.resolve(&url.segments[1..].join("/"))
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn request(headers: &[String]) -> HttpRequest {
+        let mut lines = vec!["GET /ui/app.js HTTP/1.1".to_string()];
+        lines.extend(headers.iter().cloned());
+
+        HttpRequest {
+            header: HttpRequestHeader::parse_from_string(lines.join("\r\n")).unwrap(),
+            body: None,
+            remote_address: None,
+            principal: None,
+            received: Instant::now(),
+        }
+    }
+
+    fn http_date(time: SystemTime) -> String {
+        DateTime::<Utc>::from(time).format(HTTP_DATE_FORMAT).to_string()
+    }
+
+    #[test]
+    fn serves_files_and_directory_indexes() {
+        let site = Site::create();
+
+        assert!(site.resolve("app.js").unwrap().ends_with("app.js"));
+        assert!(site.resolve("").unwrap().ends_with("index.html"));
+        assert!(site.resolve("sub/page.txt").unwrap().ends_with("page.txt"));
+        assert!(site.resolve("missing.txt").is_none());
+        // A directory without an index is not listed.
+        assert!(site.resolve("sub").is_none());
+
+        let response = site.files.serve(&request(&[]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::Ok);
+        assert_eq!(response.header.headers["Content-Type"], "text/javascript; charset=utf-8");
+        assert_eq!(response.body.unwrap(), b"let a = 1;");
+    }
+
+    #[test]
+    fn refuses_parent_directories() {
+        let site = Site::create();
+
+        assert!(site.resolve("../secret.txt").is_none());
+        assert!(site.resolve("sub/../../secret.txt").is_none());
+        assert!(site.resolve("%2e%2e/secret.txt").is_none());
+        assert!(site.resolve("sub/%2E%2E/%2e%2e/secret.txt").is_none());
+        assert!(site.files.resolve("..").is_none());
+    }
+
+    #[test]
+    fn encoded_slashes_do_not_escape_the_root() {
+        let site = Site::create();
+
+        assert!(site.resolve("..%2Fsecret.txt").is_none());
+        assert!(site.resolve("sub%2F..%2F..%2Fsecret.txt").is_none());
+        // Still a path inside the root.
+        assert!(site.resolve("sub%2Fpage.txt").is_some());
+    }
+
+    #[test]
+    fn refuses_backslashes() {
+        let site = Site::create();
+
+        assert!(site.resolve("..\\secret.txt").is_none());
+        assert!(site.resolve("..%5Csecret.txt").is_none());
+        assert!(site.resolve("sub%5Cpage.txt").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        let site = Site::create();
+        let root = site.base.join("www");
+
+        std::os::unix::fs::symlink(site.base.join("secret.txt"), root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&site.base, root.join("up")).unwrap();
+        std::os::unix::fs::symlink(root.join("app.js"), root.join("alias.js")).unwrap();
+
+        assert!(site.resolve("escape.txt").is_none());
+        assert!(site.resolve("up/secret.txt").is_none());
+        assert!(site.resolve("alias.js").is_some());
+    }
+
+    #[test]
+    fn if_none_match_compares_the_etag() {
+        let site = Site::create();
+        let etag = site.files.serve(&request(&[]), "app.js").header.headers["ETag"].clone();
+
+        for matching in [etag.clone(), format!("W/{}", etag), format!("\"other\", {}", etag), "*".to_string()] {
+            let response = site.files.serve(&request(&[format!("If-None-Match: {}", matching)]), "app.js");
+
+            assert_eq!(response.header.status, HttpStatus::NotModified, "{}", matching);
+            assert!(response.body.is_none());
+            assert!(!response.header.headers.contains_key("Content-Length"));
+        }
+
+        let response = site.files.serve(&request(&["If-None-Match: \"other\"".to_string()]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::Ok);
+    }
+
+    #[test]
+    fn if_modified_since_compares_the_modification_time() {
+        let site = Site::create();
+        let later = http_date(SystemTime::now() + Duration::from_secs(60));
+        let earlier = http_date(SystemTime::now() - Duration::from_secs(3600));
+
+        let response = site.files.serve(&request(&[format!("If-Modified-Since: {}", later)]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::NotModified);
+
+        let response = site.files.serve(&request(&[format!("If-Modified-Since: {}", earlier)]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::Ok);
+
+        let response = site.files.serve(&request(&["If-Modified-Since: yesterday".to_string()]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::Ok);
+
+        // `If-None-Match` wins when both are sent.
+        let response = site.files.serve(&request(&["If-None-Match: \"other\"".to_string(), format!("If-Modified-Since: {}", later)]), "app.js");
+        assert_eq!(response.header.status, HttpStatus::Ok);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, CertificateError, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+use crate::http::error::HttpError;
+
+/// A connection that is either plain TCP or TLS over TCP.
+/// Everything above the stream only sees `Read` and `Write`.
+pub enum NetworkStream {
+    Plain(TcpStream),
+    ServerTls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    ClientTls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+/// The certificate chain and private key files the server presents, both PEM encoded.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsServerFiles {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+}
+
+/// The PEM files of the certificates the client trusts, loaded into `TlsClientSettings`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsClientFiles {
+    /// CA certificates any server may present a certificate issued by.
+    #[serde(default)]
+    pub trusted_roots: Vec<PathBuf>,
+    /// The one certificate trusted for each host, by host name or IP address.
+    #[serde(default)]
+    pub pinned: HashMap<String, PathBuf>,
+}
+
+/// Which servers the client trusts.
+/// A pinned certificate is trusted for its host as is, which allows nodes with self-signed certificates,
+/// any other host must present a certificate issued by one of the trusted roots.
+pub struct TlsClientSettings {
+    roots: RootCertStore,
+    pinned: HashMap<String, Certificate>,
+}
+
+struct PinningVerifier {
+    roots: WebPkiVerifier,
+    pinned: HashMap<String, Certificate>,
+}
+
+impl NetworkStream {
+    /// Wrap a connection to `address` (`host:port`), the handshake is done on the first write.
+    pub fn connect(stream: TcpStream, address: &str, tls: Option<&Arc<ClientConfig>>) -> Result<NetworkStream, HttpError> {
+        match tls {
+            None => Ok(NetworkStream::Plain(stream)),
+            Some(config) => {
+                let name = ServerName::try_from(host(address)).map_err(|_| HttpError::Tls(format!("Invalid server name `{}`.", address)))?;
+                let connection = ClientConnection::new(config.clone(), name).map_err(|e| HttpError::Tls(e.to_string()))?;
+                Ok(NetworkStream::ClientTls(Box::new(StreamOwned::new(connection, stream))))
+            }
+        }
+    }
+
+    /// The underlying socket, for timeouts and addresses.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            NetworkStream::Plain(stream) => stream,
+            NetworkStream::ServerTls(stream) => stream.get_ref(),
+            NetworkStream::ClientTls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Tell the peer the connection is being closed. Only TLS has anything to send.
+    pub fn close(&mut self) {
+        match self {
+            NetworkStream::Plain(_) => {}
+            NetworkStream::ServerTls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+            NetworkStream::ClientTls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+        }
+    }
+}
+
+impl Read for NetworkStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NetworkStream::Plain(stream) => stream.read(buf),
+            NetworkStream::ServerTls(stream) => stream.read(buf),
+            NetworkStream::ClientTls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NetworkStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NetworkStream::Plain(stream) => stream.write(buf),
+            NetworkStream::ServerTls(stream) => stream.write(buf),
+            NetworkStream::ClientTls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NetworkStream::Plain(stream) => stream.flush(),
+            NetworkStream::ServerTls(stream) => stream.flush(),
+            NetworkStream::ClientTls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl TlsServerFiles {
+    /// Load the certificate chain and key into a server configuration.
+    pub fn load(&self) -> Result<Arc<ServerConfig>, HttpError> {
+        let certificates = read_certificates(&self.certificate)?;
+
+        let key = read_pem(&self.private_key)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None
+            })
+            .ok_or_else(|| HttpError::Tls(format!("No private key found in {}.", self.private_key.display())))?;
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(|e| HttpError::Tls(e.to_string()))?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+impl TlsClientFiles {
+    pub fn load(&self) -> Result<TlsClientSettings, HttpError> {
+        let mut settings = TlsClientSettings::create();
+
+        for path in &self.trusted_roots {
+            settings.add_trusted_roots(path.clone())?;
+        }
+
+        for (host, path) in &self.pinned {
+            settings.pin_certificate(host.clone(), path.clone())?;
+        }
+
+        Ok(settings)
+    }
+}
+
+impl TlsClientSettings {
+    /// Settings that trust nothing until roots are added or certificates pinned.
+    pub fn create() -> TlsClientSettings {
+        TlsClientSettings {
+            roots: RootCertStore::empty(),
+            pinned: HashMap::new(),
+        }
+    }
+
+    /// Trust every CA certificate in a PEM file.
+    pub fn add_trusted_roots(&mut self, path: PathBuf) -> Result<(), HttpError> {
+        for certificate in read_certificates(&path)? {
+            self.roots.add(&certificate).map_err(|e| HttpError::Tls(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Trust exactly the certificate in a PEM file for `host`, without checking who issued it.
+    pub fn pin_certificate(&mut self, host: String, path: PathBuf) -> Result<(), HttpError> {
+        match read_certificates(&path)?.into_iter().next() {
+            Some(certificate) => {
+                self.pinned.insert(host, certificate);
+                Ok(())
+            }
+            None => Err(HttpError::Tls(format!("No certificate found in {}.", path.display())))
+        }
+    }
+
+    pub fn build(&self) -> Arc<ClientConfig> {
+        let verifier = PinningVerifier {
+            roots: WebPkiVerifier::new(self.roots.clone(), None),
+            pinned: self.pinned.clone(),
+        };
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        Arc::new(config)
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], server_name: &ServerName, scts: &mut dyn Iterator<Item=&[u8]>, ocsp_response: &[u8], now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        let name = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => String::new()
+        };
+
+        match self.pinned.get(&name) {
+            Some(pinned) if pinned == end_entity => Ok(ServerCertVerified::assertion()),
+            // A pinned host presenting anything else is refused, even if a root would vouch for it.
+            Some(_) => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+            None => self.roots.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+        }
+    }
+}
+
+/// The host part of a `host:port` address, without the brackets around an IPv6 address.
+fn host(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => address
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read_pem(path: &PathBuf) -> Result<Vec<Item>, HttpError> {
+    let file = File::open(path).map_err(|e| HttpError::Tls(format!("Could not open {} - {}", path.display(), e)))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| HttpError::Tls(format!("Could not read {} - {}", path.display(), e)))
+}
+
+fn read_certificates(path: &PathBuf) -> Result<Vec<Certificate>, HttpError> {
+    let certificates: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None
+        })
+        .collect();
+
+    match certificates.is_empty() {
+        true => Err(HttpError::Tls(format!("No certificates found in {}.", path.display()))),
+        false => Ok(certificates)
+    }
+}
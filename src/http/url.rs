@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+/// A request target split into its decoded path, query and fragment.
+#[derive(Clone, Debug)]
+pub struct Url {
+    pub path: String,
+    pub segments: Vec<String>,
+    /// Query parameters, a key that appears more than once keeps every value in order.
+    pub query: HashMap<String, Vec<String>>,
+    pub fragment: Option<String>,
+}
+
+impl Url {
+    pub fn parse(value: &str) -> Url {
+        let (value, fragment) = match value.split_once('#') {
+            Some((v, f)) => (v, Some(decode_component(f, false))),
+            None => (value, None)
+        };
+
+        let (raw_path, raw_query) = match value.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (value, None)
+        };
+
+        // Split before decoding so an encoded `/` stays part of its segment.
+        let segments: Vec<String> = raw_path.split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| decode_component(s, false))
+            .collect();
+
+        let mut query: HashMap<String, Vec<String>> = HashMap::new();
+
+        if let Some(raw_query) = raw_query {
+            for pair in raw_query.split('&').filter(|p| !p.is_empty()) {
+                let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+
+                query.entry(decode_component(k, true)).or_default().push(decode_component(v, true));
+            }
+        }
+
+        Url {
+            path: decode_component(raw_path, false),
+            segments,
+            query,
+            fragment,
+        }
+    }
+
+    /// The first value given for a query parameter.
+    pub fn query_value(&self, key: &str) -> Option<&str> {
+        self.query.get(key).and_then(|v| v.first()).map(|v| v.as_str())
+    }
+
+    /// Every value given for a query parameter, empty if it was not given.
+    pub fn query_values(&self, key: &str) -> &[String] {
+        match self.query.get(key) {
+            Some(values) => values,
+            None => &[]
+        }
+    }
+}
+
+/// Decode `%XX` escapes. Invalid escapes are kept as they are.
+/// In query strings `+` is also decoded as a space.
+pub fn decode_component(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        decoded.push(h * 16 + l);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%')
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b)
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encode everything but unreserved characters.
+pub fn encode_component(value: &str) -> String {
+    let mut encoded = String::new();
+
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b))
+        }
+    }
+
+    encoded
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_path_query_and_fragment() {
+        let url = Url::parse("/nodes/node1/state?verbose=true#top");
+
+        assert_eq!(url.path, "/nodes/node1/state");
+        assert_eq!(url.segments, vec!["nodes", "node1", "state"]);
+        assert_eq!(url.query_value("verbose"), Some("true"));
+        assert_eq!(url.fragment.as_deref(), Some("top"));
+    }
+
+    #[test]
+    fn encode_leaves_only_unreserved_characters() {
+        assert_eq!(encode_component("a-z_0.9~"), "a-z_0.9~");
+        assert_eq!(encode_component("living room/a&b=c"), "living%20room%2Fa%26b%3Dc");
+        assert_eq!(encode_component("é"), "%C3%A9");
+        assert_eq!(decode_component(&encode_component("a+b %/?#é"), true), "a+b %/?#é");
+    }
+
+    #[test]
+    fn decodes_the_path_after_splitting_segments() {
+        let url = Url::parse("/nodes/living%20room/a%2Fb");
+
+        assert_eq!(url.path, "/nodes/living room/a/b");
+        assert_eq!(url.segments, vec!["nodes", "living room", "a/b"]);
+    }
+
+    #[test]
+    fn plus_is_only_a_space_in_the_query() {
+        let url = Url::parse("/a+b?q=a+b%2Bc&%6Bey=v%3Dw");
+
+        assert_eq!(url.path, "/a+b");
+        assert_eq!(url.query_value("q"), Some("a b+c"));
+        assert_eq!(url.query_value("key"), Some("v=w"));
+    }
+
+    #[test]
+    fn keeps_every_value_of_a_repeated_key() {
+        let url = Url::parse("/events?type=a&node=n1&type=b&flag&&=x");
+
+        assert_eq!(url.query_values("type"), ["a", "b"]);
+        assert_eq!(url.query_values("node"), ["n1"]);
+        assert_eq!(url.query_value("flag"), Some(""));
+        assert_eq!(url.query_value(""), Some("x"));
+        assert!(url.query_values("missing").is_empty());
+    }
+
+    #[test]
+    fn decodes_the_fragment() {
+        assert_eq!(Url::parse("/page#a%20b+c").fragment.as_deref(), Some("a b+c"));
+        assert_eq!(Url::parse("/page?x=1#").fragment.as_deref(), Some(""));
+        assert!(Url::parse("/page?x=%23").fragment.is_none());
+    }
+
+    #[test]
+    fn invalid_escapes_are_kept() {
+        assert_eq!(decode_component("100%", false), "100%");
+        assert_eq!(decode_component("%4", false), "%4");
+        assert_eq!(decode_component("%zz%4g", false), "%zz%4g");
+        assert_eq!(decode_component("%%41", false), "%A");
+
+        let url = Url::parse("/a%/b%2?c%=d%#e%");
+        assert_eq!(url.segments, vec!["a%", "b%2"]);
+        assert_eq!(url.query_value("c%"), Some("d%"));
+        assert_eq!(url.fragment.as_deref(), Some("e%"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(decode_component("a%FFb", false), "a\u{FFFD}b");
+        assert_eq!(decode_component("%C3%A9", false), "\u{E9}");
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1::{Digest, Sha1};
+use crate::http::common::{HttpRequest, HttpResponse, HttpStatus, HttpVerb};
+use crate::http::error::HttpError;
+
+/// Appended to the client's key to make the `Sec-WebSocket-Accept` value (RFC 6455 section 1.3).
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Control frames carry at most this much payload.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// A complete message, put back together from its frames.
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The peer is closing the connection, with its status code if it sent one.
+    Close(Option<u16>),
+}
+
+/// The server end of a WebSocket connection. Pings are answered and closes acknowledged as messages are read.
+pub struct WebSocket<S: Read + Write> {
+    stream: BufReader<S>,
+    max_message_size: usize,
+    closed: bool,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Result<Opcode, HttpError> {
+        match value {
+            0x0 => Ok(Opcode::Continuation),
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xA => Ok(Opcode::Pong),
+            _ => Err(HttpError::WebSocket("Unknown opcode."))
+        }
+    }
+
+    fn get_u8(&self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+impl Frame {
+    pub fn create(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame { fin: true, opcode, payload }
+    }
+
+    /// Read a frame sent by a client. Client frames must be masked.
+    pub fn read_from<R: Read>(stream: &mut R, max_payload: usize) -> Result<Frame, HttpError> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head)?;
+
+        if head[0] & 0x70 != 0 {
+            return Err(HttpError::WebSocket("Reserved bits set without an extension."));
+        }
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = Opcode::from_u8(head[0] & 0x0F)?;
+        let masked = head[1] & 0x80 != 0;
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                stream.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64
+        };
+
+        if !masked {
+            return Err(HttpError::WebSocket("Client frames must be masked."));
+        }
+
+        if opcode.is_control() && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(HttpError::WebSocket("Invalid control frame."));
+        }
+
+        if length > max_payload as u64 {
+            return Err(HttpError::BodyTooLarge(max_payload));
+        }
+
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask)?;
+
+        let mut payload = vec![0; length as usize];
+        stream.read_exact(&mut payload)?;
+
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    /// Write a frame from the server, which are never masked.
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> std::io::Result<()> {
+        let mut head = Vec::with_capacity(10);
+        head.push(if self.fin { 0x80 } else { 0x00 } | self.opcode.get_u8());
+
+        match self.payload.len() {
+            length if length < 126 => head.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                head.push(126);
+                head.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                head.push(127);
+                head.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        stream.write_all(&head)?;
+        stream.write_all(&self.payload)?;
+        stream.flush()
+    }
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn create(stream: BufReader<S>, max_message_size: usize) -> WebSocket<S> {
+        WebSocket { stream, max_message_size, closed: false }
+    }
+
+    /// The connection, to set timeouts or check for buffered data.
+    pub fn get_mut(&mut self) -> &mut BufReader<S> {
+        &mut self.stream
+    }
+
+    /// Read the next data message, pong or close. Fragmented messages are put back together,
+    /// and control frames sent between the fragments are handled.
+    pub fn read_message(&mut self) -> Result<Message, HttpError> {
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+
+        loop {
+            let frame = Frame::read_from(&mut self.stream, self.max_message_size)?;
+
+            match frame.opcode {
+                Opcode::Ping => {
+                    Frame::create(Opcode::Pong, frame.payload).write_to(self.stream.get_mut())?;
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let code = match frame.payload.len() >= 2 {
+                        true => Some(u16::from_be_bytes([frame.payload[0], frame.payload[1]])),
+                        false => None
+                    };
+
+                    if !self.closed {
+                        self.close(code.unwrap_or(1000))?;
+                    }
+
+                    return Ok(Message::Close(code));
+                }
+                Opcode::Text | Opcode::Binary if message.is_some() => {
+                    return Err(HttpError::WebSocket("New message started before the last one finished."));
+                }
+                Opcode::Text | Opcode::Binary => message = Some((frame.opcode, frame.payload)),
+                Opcode::Continuation => match message.as_mut() {
+                    None => return Err(HttpError::WebSocket("Continuation frame without a message.")),
+                    Some((_, payload)) => {
+                        if payload.len() + frame.payload.len() > self.max_message_size {
+                            return Err(HttpError::BodyTooLarge(self.max_message_size));
+                        }
+
+                        payload.extend(frame.payload);
+                    }
+                }
+            }
+
+            // A control frame's FIN bit says nothing about the message it was sent in the middle of.
+            if frame.fin && !frame.opcode.is_control() {
+                if let Some((opcode, payload)) = message.take() {
+                    return match opcode {
+                        Opcode::Text => String::from_utf8(payload)
+                            .map(Message::Text)
+                            .map_err(|_| HttpError::WebSocket("Text message is not valid UTF-8.")),
+                        _ => Ok(Message::Binary(payload))
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), HttpError> {
+        Frame::create(Opcode::Text, text.as_bytes().to_vec()).write_to(self.stream.get_mut())?;
+        Ok(())
+    }
+
+    pub fn send_binary(&mut self, data: Vec<u8>) -> Result<(), HttpError> {
+        Frame::create(Opcode::Binary, data).write_to(self.stream.get_mut())?;
+        Ok(())
+    }
+
+    pub fn send_ping(&mut self, data: Vec<u8>) -> Result<(), HttpError> {
+        Frame::create(Opcode::Ping, data).write_to(self.stream.get_mut())?;
+        Ok(())
+    }
+
+    /// Start closing the connection, or acknowledge the peer closing it.
+    pub fn close(&mut self, code: u16) -> Result<(), HttpError> {
+        self.closed = true;
+        Frame::create(Opcode::Close, code.to_be_bytes().to_vec()).write_to(self.stream.get_mut())?;
+        Ok(())
+    }
+}
+
+/// Check if a request asks to upgrade the connection to a WebSocket.
+pub fn is_upgrade_request(request: &HttpRequest) -> bool {
+    let header = |name: &str| request.header.headers.get(name).map(|v| v.to_lowercase()).unwrap_or_default();
+
+    request.header.verb == HttpVerb::Get
+        && header("UPGRADE") == "websocket"
+        && header("CONNECTION").split(',').any(|v| v.trim() == "upgrade")
+        && header("SEC-WEBSOCKET-VERSION") == "13"
+        && request.header.headers.contains_key("SEC-WEBSOCKET-KEY")
+}
+
+/// The `101 Switching Protocols` response accepting an upgrade request.
+pub fn handshake_response(request: &HttpRequest) -> HttpResponse {
+    let key = request.header.headers.get("SEC-WEBSOCKET-KEY").map(|k| k.trim()).unwrap_or("");
+
+    let mut headers = HashMap::new();
+    headers.insert("Upgrade".to_string(), "websocket".to_string());
+    headers.insert("Connection".to_string(), "Upgrade".to_string());
+    headers.insert("Sec-WebSocket-Accept".to_string(), accept_key(key));
+
+    let mut response = HttpResponse::create(HttpStatus::SwitchingProtocols, "text/plain".to_string(), headers, None);
+
+    // There is no body, what follows is the WebSocket connection.
+    response.header.headers.remove("Content-Length");
+    response.header.headers.remove("Content-Type");
+    response
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+
+    STANDARD.encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    /// A frame as a client sends it, masked and with the shortest length encoding.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0x00 } | opcode];
+
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    fn read(bytes: &[u8], max_payload: usize) -> Result<Frame, HttpError> {
+        Frame::read_from(&mut Cursor::new(bytes.to_vec()), max_payload)
+    }
+
+    /// Reads from `input` and keeps what is written, standing in for a connection.
+    struct TestStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socket(input: Vec<u8>) -> WebSocket<TestStream> {
+        WebSocket::create(BufReader::new(TestStream { input: Cursor::new(input), output: Vec::new() }), 1024)
+    }
+
+    #[test]
+    fn unmasks_client_frames() {
+        // The masked "Hello" example from RFC 6455 section 5.7.
+        let frame = read(&[0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58], 1024).unwrap();
+
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn reads_each_length_encoding() {
+        for length in [0, 125, 126, 65535, 65536] {
+            let payload = vec![b'x'; length];
+            let frame = read(&client_frame(true, 0x2, &payload), 70000).unwrap();
+
+            assert_eq!(frame.payload, payload, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn writes_the_shortest_length_encoding_unmasked() {
+        let write = |length: usize| {
+            let mut written = Vec::new();
+            Frame::create(Opcode::Binary, vec![b'x'; length]).write_to(&mut written).unwrap();
+            written
+        };
+
+        assert_eq!(write(125)[..2], [0x82, 125]);
+        assert_eq!(write(126)[..4], [0x82, 126, 0x00, 0x7E]);
+        assert_eq!(write(65535)[..4], [0x82, 126, 0xFF, 0xFF]);
+        assert_eq!(write(65536)[..10], [0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+        assert_eq!(write(65536).len(), 10 + 65536);
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        assert!(matches!(read(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o'], 1024), Err(HttpError::WebSocket(_))));
+    }
+
+    #[test]
+    fn rejects_reserved_bits_and_unknown_opcodes() {
+        let mut frame = client_frame(true, 0x1, b"Hello");
+        frame[0] |= 0x40;
+        assert!(matches!(read(&frame, 1024), Err(HttpError::WebSocket(_))));
+
+        assert!(matches!(read(&client_frame(true, 0x3, b"Hello"), 1024), Err(HttpError::WebSocket(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames() {
+        assert!(matches!(read(&client_frame(false, 0x9, b"ping"), 1024), Err(HttpError::WebSocket(_))));
+        assert!(matches!(read(&client_frame(true, 0x9, &[0; 126]), 1024), Err(HttpError::WebSocket(_))));
+    }
+
+    #[test]
+    fn rejects_payloads_over_the_limit_before_reading_them() {
+        let mut frame = vec![0x82, 0x80 | 127];
+        frame.extend_from_slice(&u64::MAX.to_be_bytes());
+        frame.extend_from_slice(&MASK);
+
+        assert!(matches!(read(&frame, 1024), Err(HttpError::BodyTooLarge(1024))));
+    }
+
+    #[test]
+    fn truncated_frames_are_a_closed_connection() {
+        let frame = client_frame(true, 0x1, b"Hello");
+
+        // Cut off in the head, the extended length, the mask and the payload.
+        for length in [1, 2, 4, frame.len() - 1] {
+            assert!(matches!(read(&frame[..length], 1024), Err(HttpError::ConnectionClosed)), "length {}", length);
+        }
+
+        let frame = client_frame(true, 0x2, &[0; 200]);
+        assert!(matches!(read(&frame[..3], 1024), Err(HttpError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn joins_fragments_and_answers_pings_between_them() {
+        let mut input = client_frame(false, 0x1, b"Hel");
+        input.extend(client_frame(true, 0x9, b"are you there"));
+        input.extend(client_frame(true, 0x0, b"lo"));
+
+        let mut socket = socket(input);
+
+        assert!(matches!(socket.read_message(), Ok(Message::Text(text)) if text == "Hello"));
+        assert_eq!(socket.get_mut().get_ref().output, [&[0x8A, 13][..], b"are you there"].concat());
+    }
+
+    #[test]
+    fn rejects_continuations_without_a_message() {
+        assert!(matches!(socket(client_frame(true, 0x0, b"lo")).read_message(), Err(HttpError::WebSocket(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8_text() {
+        assert!(matches!(socket(client_frame(true, 0x1, &[0xFF, 0xFE])).read_message(), Err(HttpError::WebSocket(_))));
+    }
+
+    #[test]
+    fn binary_messages_and_pongs_keep_their_payload() {
+        let mut input = client_frame(false, 0x2, &[0x00, 0xFF]);
+        input.extend(client_frame(true, 0x0, &[0x7F]));
+        input.extend(client_frame(true, 0xA, &7u32.to_be_bytes()));
+
+        let mut socket = socket(input);
+
+        assert!(matches!(socket.read_message(), Ok(Message::Binary(data)) if data == [0x00, 0xFF, 0x7F]));
+        assert!(matches!(socket.read_message(), Ok(Message::Pong(data)) if data == 7u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn sends_binary_and_pings_unmasked() {
+        let mut socket = socket(Vec::new());
+
+        socket.send_binary(vec![1, 2, 3]).unwrap();
+        socket.send_ping(b"hi".to_vec()).unwrap();
+
+        assert_eq!(socket.get_mut().get_ref().output, [0x82, 3, 1, 2, 3, 0x89, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn echoes_a_close_with_its_code() {
+        let mut socket = socket(client_frame(true, 0x8, &1001u16.to_be_bytes()));
+
+        assert!(matches!(socket.read_message(), Ok(Message::Close(Some(1001)))));
+        assert_eq!(socket.get_mut().get_ref().output, [0x88, 2, 0x03, 0xE9]);
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}
@@ -1,5 +1,6 @@
 ﻿pub mod network;
 
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
 use crate::HttpResponse;
@@ -11,6 +12,12 @@ pub struct UpdateNodeStateRequest {
     pub new_state: u8,
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeStateRequest {
+    pub new_state: u8,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateNodeStateResponse {
@@ -25,6 +32,37 @@ pub struct GetNodeStateResponse {
     pub state: u8,
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeListResponse {
+    pub nodes: Vec<String>,
+}
+
+/// A bearer token issued by `POST /auth/token`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenResponse {
+    pub token: String,
+    pub token_type: &'static str,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+/// A command sent by a WebSocket client, such as `{"type": "changeNodeState", "node": "node1", "newState": 1}`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum WebSocketCommand {
+    ChangeNodeState(UpdateNodeStateRequest),
+}
+
+/// The reply to a command sent by a WebSocket client.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum WebSocketReply {
+    Queued { id: String },
+    Error { message: String },
+}
+
 impl UpdateNodeStateRequest {
     pub fn from_bytes(bytes: Vec<u8>) -> Result<UpdateNodeStateRequest> {
         let request: UpdateNodeStateRequest = serde_json::from_slice(&bytes)?;
@@ -36,6 +74,13 @@ impl UpdateNodeStateRequest {
     }
 }
 
+impl NodeStateRequest {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<NodeStateRequest> {
+        let request: NodeStateRequest = serde_json::from_slice(&bytes)?;
+        Ok(request)
+    }
+}
+
 impl UpdateNodeStateResponse {
     pub fn from_bytes(bytes: Vec<u8>) -> Result<UpdateNodeStateResponse> {
         let response: UpdateNodeStateResponse = serde_json::from_slice(&bytes)?;
@@ -70,4 +115,24 @@ impl GetNodeStateResponse {
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         serde_json::to_vec(self)
     }
-}
\ No newline at end of file
+}
+
+impl NodeListResponse {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+impl FromStr for WebSocketCommand {
+    type Err = serde_json::Error;
+
+    fn from_str(text: &str) -> Result<WebSocketCommand> {
+        serde_json::from_str(text)
+    }
+}
+
+impl WebSocketReply {
+    pub fn to_string(&self) -> Result<String> {
+        serde_json::to_string(self)
+    }
+}
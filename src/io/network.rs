@@ -2,14 +2,29 @@
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
+use crate::common::shutdown::{join_until, receive_until, ShutdownSignal};
+use crate::http::client::HttpConnectionPool;
 
 pub struct NameResolver {
-    thread: JoinHandle<()>
+    thread: JoinHandle<()>,
+    stopping: ShutdownSignal,
 }
 
 pub enum ResolverMessage {
     AddAddress((String, String)),
     GetAddress(NameRequest),
+    /// Reply with the name of every registered node, in order.
+    ListNames(Sender<Vec<String>>),
+}
+
+/// How the controller reaches its nodes, shared by the orchestrator and the http server.
+#[derive(Clone)]
+pub struct NodeLinks {
+    /// Resolves node names to addresses.
+    pub resolver: Sender<ResolverMessage>,
+    /// Keep-alive connections to the nodes.
+    pub pool: HttpConnectionPool,
 }
 
 pub struct NameRequest {
@@ -22,10 +37,11 @@ impl NameResolver {
     
     pub fn start(mut map: HashMap<String, String>, receiver: Receiver<ResolverMessage>) -> NameResolver {
         
+        let stopping = ShutdownSignal::create();
+        let signal = stopping.clone();
+
         let thread =
-            thread::spawn(move || loop {
-               let message = receiver.recv().unwrap();
-                
+            thread::spawn(move || receive_until(&receiver, &signal, |message| {
                 match message {
                     ResolverMessage::AddAddress((k, v)) => {
                         map.insert(k, v);
@@ -41,12 +57,25 @@ impl NameResolver {
                             }
                         }
                     }
+                    ResolverMessage::ListNames(reply_channel) => {
+                        let mut names: Vec<String> = map.keys().cloned().collect();
+                        names.sort();
+
+                        // The caller may have given up waiting.
+                        let _ = reply_channel.send(names);
+                    }
                 }
-            });
+            }));
         
         NameResolver {
-            thread
+            thread,
+            stopping,
         }
     }
+
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.stopping.trigger();
+        join_until(self.thread, deadline)
+    }
     
 }
\ No newline at end of file
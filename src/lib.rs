@@ -0,0 +1,17 @@
+use crate::logging::logger;
+use crate::logging::logger::Log;
+use crate::common::{ActionResult, Action, ActionType, Operation, Command, CommandType, Event, EventType};
+use crate::http::client::HttpClient;
+use crate::http::common::HttpResponse;
+use crate::logger::Logger;
+use crate::io::network::ResolverMessage;
+
+pub mod logging;
+pub mod common;
+pub mod orchestrating;
+pub mod events;
+pub mod results;
+pub mod http;
+pub mod io;
+pub mod config;
+pub mod controller;
@@ -1,10 +1,14 @@
-﻿use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+﻿use std::io::Write;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
 use chrono::Utc;
+use crate::common::shutdown::{join_until, receive_until, ShutdownSignal};
 use crate::logging::common::{ConsoleColor, LogItem, LogItemType};
 
+#[derive(Clone)]
 pub struct Logger {
     name: String,
     sender: Sender<LogItem>,
@@ -13,6 +17,7 @@ pub struct Logger {
 pub struct Log {
     handler: JoinHandle<()>,
     sender: Sender<LogItem>,
+    stopping: ShutdownSignal,
 }
 
 impl Logger {
@@ -58,9 +63,12 @@ impl Log {
 
         let _ = sender.send(LogItem::info( "Logger".to_string(), "Starting log".to_string()));
         
-        let handler = thread::spawn(move || loop {
-            let item = receiver.recv().unwrap();
-            Log::print(item);
+        let stopping = ShutdownSignal::create();
+        let signal = stopping.clone();
+
+        let handler = thread::spawn(move || {
+            receive_until(&receiver, &signal, Log::print);
+            let _ = std::io::stdout().flush();
         });
 
 
@@ -69,8 +77,17 @@ impl Log {
         Ok(Log {
             handler,
             sender,
+            stopping,
         })
     }
+
+    /// Write out everything logged so far and stop. Anything logged afterwards is dropped.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        let _ = self.sender.send(LogItem::info("Log".to_string(), "Log stopped".to_string()));
+
+        self.stopping.trigger();
+        join_until(self.handler, deadline)
+    }
     
     pub fn get_logger(&self, name: String) -> Logger {
         Logger {
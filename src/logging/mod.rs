@@ -1,2 +1,2 @@
 ﻿pub mod logger;
-mod common;
+pub mod common;
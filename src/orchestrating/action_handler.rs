@@ -1,60 +1,86 @@
 ﻿use std::collections::HashMap;
-use std::sync::mpsc::{channel, Sender, SendError};
+use std::sync::mpsc::{channel, Sender};
 use uuid::Uuid;
-use crate::{Action, ActionResult, ActionType, Event, EventType, HttpClient, HttpResponse, Log, Logger, NameResolver, Operation, ResolverMessage};
-use crate::common::{NodeStateChangeEvent, RunResultEvent};
+use crate::{Action, ActionResult, ActionType, Event, EventType, HttpClient, Logger, Operation, ResolverMessage};
+use crate::common::{ActionFailure, NodeStateChangeEvent, RunResultEvent};
+use crate::http::client::HttpConnectionPool;
+use crate::http::error::HttpError;
 use crate::io::network::NameRequest;
 use crate::io::UpdateNodeStateResponse;
 
-pub(crate) fn handle_action(action: Action, name_resolver: Sender<ResolverMessage>, logger: Logger) -> ActionResult {
+pub(crate) fn handle_action(action: Action, name_resolver: Sender<ResolverMessage>, connection_pool: HttpConnectionPool, logger: Logger) -> ActionResult {
     //let ops = vec![];
 
     let mut ops: Vec<Operation> = Vec::new();
+    let mut node = None;
 
     logger.log_info("Action completed".to_string()).unwrap();
 
-    match action.action_type {
+    let outcome = match action.action_type {
         ActionType::Test => {
-            ops.push(Operation::Test)
+            ops.push(Operation::Test);
+            Ok("Test carried out.".to_string())
         }
         ActionType::Run(run) => {
-            ops.push(Operation::RaiseEvent(Event { id: Uuid::new_v4(), event_type: EventType::RunResult(RunResultEvent { successful: true, message: run.message }) }))
+            ops.push(Operation::RaiseEvent(Event { id: Uuid::new_v4(), event_type: EventType::RunResult(RunResultEvent { successful: true, message: run.message }) }));
+            Ok("Run carried out.".to_string())
         }
         ActionType::ChangeNodeState(new_state) => {
+            node = Some(new_state.node.clone());
+
             let (rc, rx) = channel();
             name_resolver.send(ResolverMessage::GetAddress(NameRequest { name: new_state.node.clone(), reply_channel: rc })).unwrap();
             
             match rx.recv().unwrap() {
                 None => {
                     logger.log_warning("Could not resolve name".to_string()).unwrap();
+                    Err((ActionFailure::UnknownNode, format!("Node {} is not known.", new_state.node)))
                 }
                 Some(addr) => {
-                    let mut client = HttpClient::create(addr);
+                    let mut client = HttpClient::with_pool(addr, connection_pool);
                     match client.get(format!("/set-state/{}", new_state.new_state), "text/plain".to_string(), HashMap::new()) {
+                        Ok(response) if !response.header.status.is_success() => {
+                            logger.log_error(format!("Failed to update node state. Node responded {}", response.header.status.get_code())).unwrap();
+                            Err((ActionFailure::BadResponse, format!("Node {} responded with status {}.", new_state.node, response.header.status.get_code())))
+                        }
                         Ok(response) => {
                             match UpdateNodeStateResponse::from_http_response(response) {
                                 Ok(update_response) => if update_response.result == "updated" {
                                     logger.log_success("Node state updated".to_string()).unwrap();
-                                    ops.push(Operation::RaiseEvent(Event { id: Uuid::new_v4(), event_type: EventType::NodeStateChange(NodeStateChangeEvent { node: new_state.node, old_state: update_response.old_state, new_state: update_response.new_state }) }))
+                                    let message = format!("Node {} changed from {} to {}.", new_state.node, update_response.old_state, update_response.new_state);
+                                    ops.push(Operation::RaiseEvent(Event { id: Uuid::new_v4(), event_type: EventType::NodeStateChange(NodeStateChangeEvent { node: new_state.node, old_state: update_response.old_state, new_state: update_response.new_state }) }));
+                                    Ok(message)
                                 }
                                 else {
-                                    logger.log_info(format!("Node state not updated. Requested state same as current state")).unwrap();
+                                    logger.log_info("Node state not updated. Requested state same as current state".to_string()).unwrap();
+                                    Ok(format!("Node {} is already in state {}.", new_state.node, update_response.new_state))
                                 },
                                 Err(e) => {
                                     logger.log_error(format!("Failed to update node state. Error - {}", e)).unwrap();
+                                    Err((ActionFailure::BadResponse, format!("Node {} sent an invalid response - {}", new_state.node, e)))
                                 }
                             }
                         }
-                        Err(e) => {
+                        Err(HttpError::Timeout) => {
+                            logger.log_warning(format!("Node {} did not respond in time, it may be offline.", new_state.node)).unwrap();
+                            Err((ActionFailure::Timeout, format!("Node {} did not respond in time, it may be offline.", new_state.node)))
+                        }
+                        Err(e @ (HttpError::Connection(_) | HttpError::Io(_) | HttpError::Tls(_))) => {
                             logger.log_error(format!("Failed to connect to node. Error - {}", e)).unwrap();
+                            Err((ActionFailure::Unreachable, format!("Could not connect to node {} - {}", new_state.node, e)))
+                        }
+                        Err(e) => {
+                            logger.log_error(format!("Failed to update node state. Error - {}", e)).unwrap();
+                            Err((ActionFailure::BadResponse, format!("Node {} sent an invalid response - {}", new_state.node, e)))
                         }
                     }
                 }
             }
-            
-            
         }
-    }
+    };
 
-    ActionResult { id: action.id, successful: true, message: "Hello, World!".to_string(), ops }
-}
\ No newline at end of file
+    match outcome {
+        Ok(message) => ActionResult { id: action.id, node, successful: true, message, failure: None, ops },
+        Err((failure, message)) => ActionResult { id: action.id, node, successful: false, message, failure: Some(failure), ops }
+    }
+}
@@ -1,23 +1,28 @@
 ﻿mod action_handler;
 
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::{Arc, mpsc, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use crate::{Action, ActionResult, ActionType, Command, CommandType, Log, Logger, ResolverMessage};
-use crate::common::{ChangeNodeStateAction, RunAction};
+use std::time::Instant;
+use crate::{Action, ActionResult, ActionType, Command, CommandType, Log, Logger};
+use crate::common::{ActionFailure, ChangeNodeStateAction, RunAction};
+use crate::common::permissions::{AccessPolicy, Permission};
+use crate::common::queue::{bounded, QueueError, QueueReceiver, QueueSender, QueueSettings};
+use crate::common::shutdown::{join_until, receive_until, ShutdownSignal};
+use crate::io::network::NodeLinks;
 use crate::orchestrating::action_handler::handle_action;
 
 type Job = Box<dyn FnOnce() -> ActionResult + Send + 'static>;
 
 pub(crate) struct Orchestrator {
-    sender: Sender<Command>,
+    sender: QueueSender<Command>,
     thread: JoinHandle<()>,
+    stopping: ShutdownSignal,
 }
 
 pub(crate) struct WorkerPool {
     workers: Vec<Worker>,
-    sender: Sender<Job>,
+    sender: QueueSender<Job>,
     logger: Logger,
 }
 
@@ -27,39 +32,76 @@ struct Worker {
 }
 
 impl Orchestrator {
-    pub fn start(result_sender: Sender<ActionResult>, command_receiver: Receiver<Command>, command_sender: Sender<Command>, nr_sender: Sender<ResolverMessage>, log: &Log) -> Orchestrator {
+    pub fn start(result_sender: QueueSender<ActionResult>, command_receiver: QueueReceiver<Command>, command_sender: QueueSender<Command>, job_queue: QueueSettings, nodes: NodeLinks, access: Option<Arc<AccessPolicy>>, log: &Log) -> Orchestrator {
         //let (tx, rx) = channel::<Command>();
         let logger = log.get_logger("orchestrator".to_string());
 
         logger.log_info("Starting".to_string()).unwrap();
-        let workers = WorkerPool::new(4, result_sender.clone(), log);
+        let workers = WorkerPool::new(4, job_queue, result_sender.clone(), log);
 
-        let thread = thread::spawn(move || loop {
-            let command = command_receiver.recv().unwrap();
+        let stopping = ShutdownSignal::create();
+        let signal = stopping.clone();
 
-            logger.log_info(format!("Command {} received", command.id)).unwrap();
+        let thread = thread::spawn(move || {
+            receive_until(&command_receiver, &signal, |command| {
+                match &command.principal {
+                    Some(principal) => logger.log_info(format!("Command {} received from {}", command.id, principal.name)).unwrap(),
+                    None => logger.log_info(format!("Command {} received", command.id)).unwrap()
+                }
 
-            // Handle turning the command into an action.
-            let action =
-                match command.command_type {
-                    CommandType::Test => {
-                        Action { id: command.id, action_type: ActionType::Test }
-                    }
-                    CommandType::Run(run_command) => {
-                        Action { id: command.id, action_type: ActionType::Run(RunAction { message: run_command.message }) }
-                    }
-                    CommandType::ChangeNodeState(new_state) => {
-                        Action { id: command.id, action_type: ActionType::ChangeNodeState(ChangeNodeStateAction { node: new_state.node, new_state: new_state.new_state }) }
-                    }
+                let node = match &command.command_type {
+                    CommandType::ChangeNodeState(change) => Some(change.node.clone()),
+                    CommandType::Test | CommandType::Run(_) => None
                 };
 
-            let action_logger = logger.create_from(format!("action_{}", action.id));
+                if !authorized(&command, access.as_deref()) {
+                    logger.log_warning(format!("Command {} refused, permission denied.", command.id)).unwrap();
+                    if let Err(e) = result_sender.send(ActionResult { id: command.id, node, successful: false, message: "Permission denied.".to_string(), failure: Some(ActionFailure::PermissionDenied), ops: vec![] }) {
+                        logger.log_error(format!("Result dropped - {}", e)).unwrap();
+                    }
+                    return;
+                }
+
+                // Handle turning the command into an action.
+                let action =
+                    match command.command_type {
+                        CommandType::Test => {
+                            Action { id: command.id, action_type: ActionType::Test }
+                        }
+                        CommandType::Run(run_command) => {
+                            Action { id: command.id, action_type: ActionType::Run(RunAction { message: run_command.message }) }
+                        }
+                        CommandType::ChangeNodeState(new_state) => {
+                            Action { id: command.id, action_type: ActionType::ChangeNodeState(ChangeNodeStateAction { node: new_state.node, new_state: new_state.new_state }) }
+                        }
+                    };
+
+                let action_logger = logger.create_from(format!("action_{}", action.id));
+
+                let name_resolver = nodes.resolver.clone();
+                let pool = nodes.pool.clone();
+                let id = action.id;
+
+                if let Err(e) = workers.execute(|| handle_action(action, name_resolver, pool, action_logger)) {
+                    logger.log_warning(format!("Command {} refused - {}", id, e)).unwrap();
+                    if let Err(e) = result_sender.send(ActionResult { id, node, successful: false, message: "Too many actions waiting to be carried out.".to_string(), failure: Some(ActionFailure::Rejected), ops: vec![] }) {
+                        logger.log_error(format!("Result dropped - {}", e)).unwrap();
+                    }
+                }
+            });
 
-            let name_resolver = nr_sender.clone();
-            workers.execute(|| handle_action(action, name_resolver, action_logger));
+            // Let the workers finish the actions already handed to them.
+            workers.shutdown();
+            logger.log_info("Stopped".to_string()).unwrap();
         });
 
-        Orchestrator { sender: command_sender, thread }
+        Orchestrator { sender: command_sender, thread, stopping }
+    }
+
+    /// Carry out the commands already queued, wait for their actions to finish, then stop.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.stopping.trigger();
+        join_until(self.thread, deadline)
     }
 
     pub fn queue_command(&self, command: Command) {
@@ -68,12 +110,12 @@ impl Orchestrator {
 }
 
 impl WorkerPool {
-    pub fn new(size: usize, result_handler: Sender<ActionResult>, log: &Log) -> WorkerPool {
+    pub fn new(size: usize, queue: QueueSettings, result_handler: QueueSender<ActionResult>, log: &Log) -> WorkerPool {
         let logger = log.get_logger("worker_pool".to_string());
 
         logger.log_info("Starting".to_string()).unwrap();
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = bounded(queue);
 
         let mut workers = Vec::with_capacity(size);
 
@@ -91,19 +133,36 @@ impl WorkerPool {
         WorkerPool { workers, sender, logger }
     }
 
-    pub fn execute<F>(&self, f: F) where
+    pub fn execute<F>(&self, f: F) -> Result<(), QueueError<Job>> where
         F: FnOnce() -> ActionResult + Send + 'static,
     {
         let job = Box::new(f);
 
-        self.sender.send(job).unwrap();
+        self.sender.send(job)
+    }
+
+    /// Wait for every queued job to be done, then stop the workers.
+    pub fn shutdown(self) {
+        // Workers stop once the queue is empty and nothing can be added to it.
+        drop(self.sender);
+
+        for worker in self.workers {
+            if worker.thread.join().is_err() {
+                self.logger.log_error(format!("Worker {} panicked", worker.id)).unwrap();
+            }
+        }
+
+        self.logger.log_info("Stopped".to_string()).unwrap();
     }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, result_handler: Sender<ActionResult>, logger: Logger) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<QueueReceiver<Job>>>, result_handler: QueueSender<ActionResult>, logger: Logger) -> Worker {
         let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break
+            };
 
             logger.log_info("Job received".to_string()).unwrap();
 
@@ -114,9 +173,25 @@ impl Worker {
                 false => logger.log_error("Job failed".to_string()).unwrap(),
             };
 
-            result_handler.send(result).unwrap();
+            if let Err(e) = result_handler.send(result) {
+                logger.log_error(format!("Result dropped - {}", e)).unwrap();
+            }
         });
 
         Worker { id, thread }
     }
-}
\ No newline at end of file
+}
+
+/// Check the principal that asked for a command may have it carried out.
+/// Commands raised by the controller itself have no principal and are always allowed.
+fn authorized(command: &Command, access: Option<&AccessPolicy>) -> bool {
+    let (access, principal) = match (access, &command.principal) {
+        (Some(access), Some(principal)) => (access, principal),
+        _ => return true
+    };
+
+    match &command.command_type {
+        CommandType::ChangeNodeState(change) => access.allows(principal, Permission::ChangeState, Some(&change.node)),
+        CommandType::Test | CommandType::Run(_) => true
+    }
+}
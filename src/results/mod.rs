@@ -1,32 +1,47 @@
 ﻿mod result_handler;
 
-use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
 use crate::{ActionResult, Log};
 use crate::common::Event;
+use crate::common::queue::{QueueReceiver, QueueSender};
+use crate::common::shutdown::{join_until, receive_until, ShutdownSignal};
 use crate::results::result_handler::handle_result;
 
 pub(crate) struct ResultHandler {
-    thread: JoinHandle<()>
+    thread: JoinHandle<()>,
+    stopping: ShutdownSignal,
 }
 
 impl ResultHandler {
-    pub fn start(event_sender: Sender<Event>, result_receiver: Receiver<ActionResult>, log: &Log) -> ResultHandler {
+    pub fn start(event_sender: QueueSender<Event>, result_receiver: QueueReceiver<ActionResult>, log: &Log) -> ResultHandler {
         let logger = log.get_logger("result_handler".to_string());
 
         logger.log_info("Starting".to_string()).unwrap();
         
-        let thread = thread::spawn(move || loop {
-            let result = result_receiver.recv().unwrap();
-
-            let events = handle_result(result, &logger);
-            
-            for event in events {
-                event_sender.send(event).unwrap();
-            }
+        let stopping = ShutdownSignal::create();
+        let signal = stopping.clone();
+
+        let thread = thread::spawn(move || {
+            receive_until(&result_receiver, &signal, |result| {
+                let events = handle_result(result, &logger);
+
+                for event in events {
+                    // The event loop stops first, the last results have nowhere to raise events.
+                    let _ = event_sender.send(event);
+                }
+            });
+
+            logger.log_info("Stopped".to_string()).unwrap();
         });
 
-        ResultHandler { thread }
+        ResultHandler { thread, stopping }
+    }
+
+    /// Handle the results already sent, then stop.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        self.stopping.trigger();
+        join_until(self.thread, deadline)
     }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 ﻿use crate::{ActionResult, Logger, Operation};
-use crate::common::Event;
+use uuid::Uuid;
+use crate::common::{ActionResultEvent, Event, EventType};
 
 pub fn handle_result(result: ActionResult, logger: &Logger) -> Vec<Event> {
     match result.successful {
@@ -7,7 +8,16 @@ pub fn handle_result(result: ActionResult, logger: &Logger) -> Vec<Event> {
         false => logger.log_error(format!("Action {} failed. Message - {}", result.id, result.message)).unwrap(),
     };
     
-    let mut events: Vec<Event> = Vec::new();
+    let mut events: Vec<Event> = vec![Event {
+        id: Uuid::new_v4(),
+        event_type: EventType::ActionResult(ActionResultEvent {
+            action_id: result.id,
+            node: result.node,
+            successful: result.successful,
+            message: result.message,
+            failure: result.failure,
+        }),
+    }];
     
     for op in result.ops {
         match op {